@@ -0,0 +1,83 @@
+//! The per-user cache directory of nixci
+use std::{
+    io::ErrorKind,
+    os::unix::fs::{DirBuilderExt, MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+/// Return `$XDG_CACHE_HOME/nixci/<name>` (defaulting to `~/.cache`), creating it if missing
+///
+/// The directory, like the `nixci` one, is private to the current user (see
+/// [private_dir]), so that other users cannot plant files nixci then uses.
+pub fn cache_dir(name: &str) -> Result<PathBuf> {
+    let base = std::env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .context("Unable to locate the cache directory; set $XDG_CACHE_HOME or $HOME")?;
+    std::fs::create_dir_all(&base)
+        .with_context(|| format!("Unable to create {}", base.display()))?;
+    let nixci = base.join("nixci");
+    private_dir(&nixci)?;
+    let dir = nixci.join(name);
+    private_dir(&dir)?;
+    Ok(dir)
+}
+
+/// Create `dir` (only accessible to the current user) if missing
+///
+/// Fails if `dir` already exists but is not a directory owned by the current
+/// user, or is writable by others.
+pub fn private_dir(dir: &Path) -> Result<()> {
+    match std::fs::DirBuilder::new().mode(0o700).create(dir) {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => {}
+        Err(err) => {
+            return Err(err).with_context(|| format!("Unable to create {}", dir.display()));
+        }
+    }
+    let metadata = std::fs::symlink_metadata(dir)
+        .with_context(|| format!("Unable to access {}", dir.display()))?;
+    // SAFETY: `geteuid` cannot fail, and has no side effects
+    let uid = unsafe { libc::geteuid() };
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o022 != 0 {
+        anyhow::bail!(
+            "Refusing to use {}, which is not a directory owned by (and only writable by) the current user",
+            dir.display()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_private_dir() -> Result<()> {
+        let base = std::env::temp_dir().join(format!("nixci-test-cache-{}", std::process::id()));
+        private_dir(&base)?;
+        assert_eq!(std::fs::metadata(&base)?.mode() & 0o777, 0o700);
+        // Reusing our own directory is fine
+        private_dir(&base)?;
+
+        let shared = base.join("shared");
+        std::fs::create_dir(&shared)?;
+        std::fs::set_permissions(&shared, std::fs::Permissions::from_mode(0o777))?;
+        assert!(private_dir(&shared).is_err());
+
+        let file = base.join("file");
+        std::fs::write(&file, "")?;
+        assert!(private_dir(&file).is_err());
+
+        let link = base.join("link");
+        std::os::unix::fs::symlink(&shared, &link)?;
+        assert!(private_dir(&link).is_err());
+
+        std::fs::remove_dir_all(&base)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,50 @@
+/// Buildkite dynamic pipeline generation
+///
+/// See <https://buildkite.com/docs/pipelines/defining-steps#dynamic-pipelines>
+use anyhow::Result;
+use nix_rs::flake::system::System;
+use serde::{Deserialize, Serialize};
+
+use super::{build_command, matrix_entries, MatrixEmitter, RunnerLabels};
+use crate::config::Config;
+
+/// A Buildkite command step building a single sub-flake on a single system
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildkiteStep {
+    pub label: String,
+    /// The `nixci build` invocation for this step
+    pub command: String,
+    /// Agent targeting rules; agents are expected to be tagged with `system`
+    pub agents: BuildkiteAgents,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildkiteAgents {
+    /// The runner label of the system (the system name, unless configured)
+    pub system: String,
+}
+
+/// A Buildkite pipeline, to be passed to `buildkite-agent pipeline upload`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildkitePipeline {
+    pub steps: Vec<BuildkiteStep>,
+}
+
+impl MatrixEmitter for BuildkitePipeline {
+    fn from_config(systems: &[System], cfg: &Config, runners: &RunnerLabels) -> Self {
+        let steps = matrix_entries(systems, cfg)
+            .map(|(system, name, subflake)| BuildkiteStep {
+                label: format!("{}.{} ({})", cfg.name, name, system),
+                command: build_command(system, cfg, name),
+                agents: BuildkiteAgents {
+                    system: runners.resolve(system, subflake),
+                },
+            })
+            .collect();
+        BuildkitePipeline { steps }
+    }
+
+    fn render(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
@@ -0,0 +1,20 @@
+/// Forgejo (and Gitea) Actions matrix
+///
+/// Forgejo Actions is compatible with GitHub Actions workflow syntax, so the
+/// matrix has the same shape as [GitHubMatrix].
+use nix_rs::flake::system::System;
+use serde::{Deserialize, Serialize};
+
+use super::{github::GitHubMatrix, MatrixEmitter, RunnerLabels};
+use crate::config::Config;
+
+/// A Forgejo Actions [matrix](https://forgejo.org/docs/latest/user/actions/#jobsjob_idstrategymatrix)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ForgejoMatrix(pub GitHubMatrix);
+
+impl MatrixEmitter for ForgejoMatrix {
+    fn from_config(systems: &[System], cfg: &Config, runners: &RunnerLabels) -> Self {
+        ForgejoMatrix(GitHubMatrix::from_config(systems, cfg, runners))
+    }
+}
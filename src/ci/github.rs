@@ -0,0 +1,43 @@
+/// GitHub Actions matrix
+use nix_rs::flake::system::System;
+use serde::{Deserialize, Serialize};
+
+use super::{build_command, flake_attr, matrix_entries, MatrixEmitter, RunnerLabels};
+use crate::config::Config;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GitHubMatrixRow {
+    pub system: System,
+    pub subflake: String,
+    /// Configuration name (nixci.<name>)
+    pub config: String,
+    /// The flake attribute selecting the sub-flake, e.g. `default.dev`
+    pub flake_attr: String,
+    /// The `nixci build` invocation for this row
+    pub command: String,
+    /// The runner label to build on
+    #[serde(rename = "runs-on")]
+    pub runs_on: String,
+}
+
+/// A GitHub Actions [matrix](https://docs.github.com/en/actions/using-jobs/using-a-matrix-for-your-jobs)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GitHubMatrix {
+    pub include: Vec<GitHubMatrixRow>,
+}
+
+impl MatrixEmitter for GitHubMatrix {
+    fn from_config(systems: &[System], cfg: &Config, runners: &RunnerLabels) -> Self {
+        let include = matrix_entries(systems, cfg)
+            .map(|(system, name, subflake)| GitHubMatrixRow {
+                system: system.clone(),
+                subflake: name.clone(),
+                config: cfg.name.clone(),
+                flake_attr: flake_attr(cfg, name),
+                command: build_command(system, cfg, name),
+                runs_on: runners.resolve(system, subflake),
+            })
+            .collect();
+        GitHubMatrix { include }
+    }
+}
@@ -0,0 +1,54 @@
+/// GitLab CI dynamic child-pipeline generation
+///
+/// See <https://docs.gitlab.com/ee/ci/pipelines/downstream_pipelines.html#dynamic-child-pipelines>
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use nix_rs::flake::system::System;
+use serde::{Deserialize, Serialize};
+
+use super::{build_command, matrix_entries, MatrixEmitter, RunnerLabels};
+use crate::config::Config;
+
+/// A GitLab CI job building a single sub-flake on a single system
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GitLabJob {
+    /// Runner tags; the runner label of the system is used as the tag
+    pub tags: Vec<String>,
+    /// The `nixci build` invocation for this job
+    pub script: Vec<String>,
+}
+
+/// A GitLab CI child pipeline, mapping job names to jobs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct GitLabPipeline(pub BTreeMap<String, GitLabJob>);
+
+impl MatrixEmitter for GitLabPipeline {
+    fn from_config(systems: &[System], cfg: &Config, runners: &RunnerLabels) -> Self {
+        let mut jobs: BTreeMap<String, GitLabJob> = matrix_entries(systems, cfg)
+            .map(|(system, name, subflake)| {
+                let job = GitLabJob {
+                    tags: vec![runners.resolve(system, subflake)],
+                    script: vec![build_command(system, cfg, name)],
+                };
+                (format!("{}-{}", name, system), job)
+            })
+            .collect();
+        // GitLab rejects a pipeline without jobs
+        if jobs.is_empty() {
+            jobs.insert(
+                "nixci-empty".to_string(),
+                GitLabJob {
+                    tags: vec![],
+                    script: vec!["echo 'nixci: nothing to build'".to_string()],
+                },
+            );
+        }
+        GitLabPipeline(jobs)
+    }
+
+    fn render(&self) -> Result<String> {
+        Ok(serde_yaml::to_string(self)?)
+    }
+}
@@ -0,0 +1,290 @@
+//! CI matrix generation for various CI systems
+//!
+//! Each CI system gets a type implementing [MatrixEmitter], which turns a
+//! nixci [Config] and a list of systems into that CI system's configuration.
+use std::{collections::BTreeMap, str::FromStr};
+
+use anyhow::Result;
+use nix_rs::flake::system::System;
+use serde::Serialize;
+
+use crate::{
+    config::{Config, SubFlakish},
+    nix::system_list::SystemsListFlakeRef,
+};
+
+pub mod buildkite;
+pub mod forgejo;
+pub mod github;
+pub mod gitlab;
+pub mod woodpecker;
+
+/// A CI configuration that builds each sub-flake on each system
+pub trait MatrixEmitter: Serialize + Sized {
+    /// Create the CI configuration for the given systems and nixci [Config]
+    fn from_config(systems: &[System], cfg: &Config, runners: &RunnerLabels) -> Self;
+
+    /// Render the CI configuration in the format expected by the CI system
+    ///
+    /// Defaults to JSON.
+    fn render(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+}
+
+/// The CI systems for which a matrix can be generated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum MatrixFormat {
+    /// GitHub Actions matrix (JSON)
+    Github,
+    /// GitLab CI dynamic child pipeline (YAML)
+    Gitlab,
+    /// Buildkite pipeline upload (JSON)
+    Buildkite,
+    /// Woodpecker CI matrix (YAML)
+    Woodpecker,
+    /// Forgejo/Gitea Actions matrix (JSON)
+    Forgejo,
+}
+
+impl MatrixFormat {
+    /// Render the CI configuration of this format
+    pub fn emit(&self, systems: &[System], cfg: &Config, runners: &RunnerLabels) -> Result<String> {
+        match self {
+            MatrixFormat::Github => {
+                github::GitHubMatrix::from_config(systems, cfg, runners).render()
+            }
+            MatrixFormat::Gitlab => {
+                gitlab::GitLabPipeline::from_config(systems, cfg, runners).render()
+            }
+            MatrixFormat::Buildkite => {
+                buildkite::BuildkitePipeline::from_config(systems, cfg, runners).render()
+            }
+            MatrixFormat::Woodpecker => {
+                woodpecker::WoodpeckerMatrix::from_config(systems, cfg, runners).render()
+            }
+            MatrixFormat::Forgejo => {
+                forgejo::ForgejoMatrix::from_config(systems, cfg, runners).render()
+            }
+        }
+    }
+}
+
+/// Return the (system, sub-flake name, sub-flake) triples to build
+///
+/// Only selected sub-flakes are included, and only for the systems they can be
+/// built on.
+pub fn matrix_entries<'a>(
+    systems: &'a [System],
+    cfg: &'a Config,
+) -> impl Iterator<Item = (&'a System, &'a String, &'a SubFlakish)> {
+    systems.iter().flat_map(move |system| {
+        cfg.subflakes
+            .0
+            .iter()
+            .filter(move |&(k, v)| {
+                cfg.is_selected(k) && v.can_build_on(std::slice::from_ref(system))
+            })
+            .map(move |(k, v)| (system, k, v))
+    })
+}
+
+/// A CI runner label for a system, parsed from `SYSTEM=LABEL`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunnerLabel {
+    pub system: System,
+    pub label: String,
+}
+
+impl FromStr for RunnerLabel {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<RunnerLabel, String> {
+        match s.split_once('=') {
+            Some((system, label)) if !system.is_empty() && !label.is_empty() => Ok(RunnerLabel {
+                system: System::from(system),
+                label: label.to_string(),
+            }),
+            _ => Err(format!("expected SYSTEM=LABEL, got '{}'", s)),
+        }
+    }
+}
+
+/// Mapping of systems to the CI runner labels to build them on
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunnerLabels(pub BTreeMap<System, String>);
+
+impl From<Vec<RunnerLabel>> for RunnerLabels {
+    fn from(labels: Vec<RunnerLabel>) -> Self {
+        RunnerLabels(labels.into_iter().map(|l| (l.system, l.label)).collect())
+    }
+}
+
+impl RunnerLabels {
+    /// The runner label for building the sub-flake on the given system
+    ///
+    /// The sub-flake's `runsOn` takes precedence, and the system name itself
+    /// is used if no label is configured.
+    pub fn resolve(&self, system: &System, subflake: &SubFlakish) -> String {
+        subflake
+            .runs_on
+            .get(system)
+            .or_else(|| self.0.get(system))
+            .cloned()
+            .unwrap_or_else(|| system.to_string())
+    }
+}
+
+/// The flake attribute selecting the given sub-flake, e.g. `default.dev`
+pub fn flake_attr(cfg: &Config, subflake: &str) -> String {
+    format!("{}.{}", cfg.name, subflake)
+}
+
+/// The `nixci build` command line to build the given sub-flake on the given system
+///
+/// The `--systems` value is the same that `nixci build` resolves the system
+/// name to (see [SystemsListFlakeRef]).
+pub fn build_command(system: &System, cfg: &Config, subflake: &str) -> String {
+    let url = cfg.flake_url.with_attr(&flake_attr(cfg, subflake));
+    let systems = SystemsListFlakeRef::from_str(system.as_ref()).unwrap();
+    format!(
+        "nixci build --systems {} {}",
+        shell_quote(&systems.0 .0),
+        shell_quote(&url.0)
+    )
+}
+
+/// Quote `s` as a single POSIX shell word, if it contains any special character
+fn shell_quote(s: &str) -> String {
+    let is_safe = |(i, c): (usize, char)| {
+        c.is_ascii_alphanumeric() || "_-+=@%:,./".contains(c) || (c == '#' && i > 0)
+    };
+    if !s.is_empty() && s.char_indices().all(is_safe) {
+        s.to_string()
+    } else {
+        format!("'{}'", s.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SubFlakish;
+    use nix_rs::flake::url::FlakeUrl;
+    use std::collections::BTreeMap;
+
+    /// A configuration with sub-flakes restricted to various systems
+    fn fixture() -> (Vec<System>, Config) {
+        let cfg = Config::for_test(
+            "default",
+            vec![
+                ("dev", SubFlakish::default()),
+                (
+                    "darwin",
+                    SubFlakish {
+                        dir: "./darwin".to_string(),
+                        systems: Some(vec!["aarch64-darwin".into()]),
+                        runs_on: BTreeMap::from([(
+                            "aarch64-darwin".into(),
+                            "macos-14".to_string(),
+                        )]),
+                        ..SubFlakish::default()
+                    },
+                ),
+                (
+                    "docs",
+                    SubFlakish {
+                        dir: "./docs".to_string(),
+                        systems: Some(vec!["x86_64-linux".into()]),
+                        ..SubFlakish::default()
+                    },
+                ),
+            ],
+        );
+        (vec!["x86_64-linux".into(), "aarch64-darwin".into()], cfg)
+    }
+
+    fn assert_golden(format: MatrixFormat, golden: &str) {
+        let (systems, cfg) = fixture();
+        let runners = RunnerLabels::from(vec!["x86_64-linux=ubuntu-latest".parse().unwrap()]);
+        let output = format.emit(&systems, &cfg, &runners).unwrap();
+        assert_eq!(output.trim_end(), golden.trim_end(), "{:?}", format);
+    }
+
+    #[test]
+    fn test_runner_label() {
+        assert_eq!(
+            RunnerLabel::from_str("x86_64-linux=nixos"),
+            Ok(RunnerLabel {
+                system: "x86_64-linux".into(),
+                label: "nixos".to_string()
+            })
+        );
+        assert!(RunnerLabel::from_str("x86_64-linux").is_err());
+        assert!(RunnerLabel::from_str("=nixos").is_err());
+    }
+
+    #[test]
+    fn test_build_command() {
+        let (_, mut cfg) = fixture();
+        assert_eq!(
+            build_command(&"x86_64-linux".into(), &cfg, "dev"),
+            "nixci build --systems github:nix-systems/x86_64-linux .#default.dev"
+        );
+        cfg.flake_url = FlakeUrl("github:srid/nixci?ref=main&dir=it's".to_string());
+        assert_eq!(
+            build_command(&"riscv64-linux".into(), &cfg, "dev"),
+            r"nixci build --systems riscv64-linux 'github:srid/nixci?ref=main&dir=it'\''s#default.dev'"
+        );
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(
+            shell_quote("github:srid/nixci#default"),
+            "github:srid/nixci#default"
+        );
+        assert_eq!(shell_quote("#default"), "'#default'");
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    #[test]
+    fn test_declared_systems() {
+        let (_, cfg) = fixture();
+        assert_eq!(
+            cfg.subflakes.declared_systems(),
+            vec![System::from("aarch64-darwin"), System::from("x86_64-linux")]
+        );
+    }
+
+    #[test]
+    fn test_github() {
+        assert_golden(MatrixFormat::Github, include_str!("testdata/github.json"));
+    }
+
+    #[test]
+    fn test_gitlab() {
+        assert_golden(MatrixFormat::Gitlab, include_str!("testdata/gitlab.yml"));
+    }
+
+    #[test]
+    fn test_buildkite() {
+        assert_golden(
+            MatrixFormat::Buildkite,
+            include_str!("testdata/buildkite.json"),
+        );
+    }
+
+    #[test]
+    fn test_woodpecker() {
+        assert_golden(
+            MatrixFormat::Woodpecker,
+            include_str!("testdata/woodpecker.yml"),
+        );
+    }
+
+    #[test]
+    fn test_forgejo() {
+        assert_golden(MatrixFormat::Forgejo, include_str!("testdata/forgejo.json"));
+    }
+}
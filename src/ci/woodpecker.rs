@@ -0,0 +1,52 @@
+/// Woodpecker CI matrix
+///
+/// See <https://woodpecker-ci.org/docs/usage/matrix-workflows>
+use anyhow::Result;
+use nix_rs::flake::system::System;
+use serde::{Deserialize, Serialize};
+
+use super::{build_command, matrix_entries, MatrixEmitter, RunnerLabels};
+use crate::config::Config;
+
+/// A row of the matrix, available as `${SYSTEM}`, `${CONFIG}`, `${SUBFLAKE}`
+/// and `${COMMAND}` in the workflow
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub struct WoodpeckerMatrixRow {
+    pub system: System,
+    pub config: String,
+    pub subflake: String,
+    /// The `nixci build` invocation for this row
+    pub command: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WoodpeckerMatrixInclude {
+    pub include: Vec<WoodpeckerMatrixRow>,
+}
+
+/// The `matrix` section of a Woodpecker workflow
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WoodpeckerMatrix {
+    pub matrix: WoodpeckerMatrixInclude,
+}
+
+impl MatrixEmitter for WoodpeckerMatrix {
+    fn from_config(systems: &[System], cfg: &Config, _runners: &RunnerLabels) -> Self {
+        let include = matrix_entries(systems, cfg)
+            .map(|(system, name, _subflake)| WoodpeckerMatrixRow {
+                system: system.clone(),
+                config: cfg.name.clone(),
+                subflake: name.clone(),
+                command: build_command(system, cfg, name),
+            })
+            .collect();
+        WoodpeckerMatrix {
+            matrix: WoodpeckerMatrixInclude { include },
+        }
+    }
+
+    fn render(&self) -> Result<String> {
+        Ok(serde_yaml::to_string(self)?)
+    }
+}
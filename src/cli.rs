@@ -1,4 +1,4 @@
-use std::str::FromStr;
+use std::{num::NonZeroUsize, path::PathBuf, str::FromStr};
 
 use anyhow::Result;
 use clap::{Parser, Subcommand};
@@ -10,11 +10,13 @@ use nix_rs::{
 };
 
 use crate::{
+    ci::{MatrixFormat, RunnerLabel},
     config,
     github::pull_request::{PullRequest, PullRequestRef},
     nix::{
-        devour_flake,
-        system_list::{SystemsList, SystemsListFlakeRef},
+        devour_flake::{self, OutputCategory, OutputFilter},
+        nix_store::{ClosureKind, StoreBackend},
+        system_list::{SystemOrList, SystemsList, SystemsListFlakeRef},
     },
 };
 
@@ -65,6 +67,16 @@ pub struct CliArgs {
     #[command(flatten)]
     pub nixcmd: NixCmd,
 
+    /// The Nix store to build in and query
+    ///
+    /// Passed to every Nix invocation that builds, or queries, store paths
+    /// (but not to the evaluation of the nixci configuration, or of systems
+    /// lists, themselves). Any
+    /// store URI accepted by `nix --store` can be used, eg: `ssh-ng://host` or
+    /// a chroot store like `/tmp/store`.
+    #[arg(long, global = true, value_name = "STORE_URI")]
+    pub store: Option<String>,
+
     #[clap(subcommand)]
     pub command: Command,
 }
@@ -79,41 +91,85 @@ impl CliArgs {
 
     // Pre-process `CliArgs`
     pub async fn preprocess(&mut self) -> anyhow::Result<()> {
+        // Completion does not need Nix, and must stay fast; flakes are enabled
+        // only when it needs to evaluate the flake.
+        if let Command::Completion { .. } | Command::Complete { .. } = self.command {
+            return Ok(());
+        }
         // Avoid using `--extra-experimental-features` if possible.
         self.nixcmd = self.nixcmd.with_flakes().await?;
         // Adjust to devour_flake's expectations
         if let Command::Build(build_cfg) = &mut self.command {
             devour_flake::transform_override_inputs(&mut build_cfg.extra_nix_build_args);
+            // A single system not among the `github:nix-systems` lists
+            if let Ok(SystemOrList::System(system)) = build_cfg.systems.0 .0.parse() {
+                build_cfg.systems = SystemsListFlakeRef::from_systems(&[system])?;
+            }
         }
         Ok(())
     }
 }
 
 #[derive(Debug, Subcommand)]
+// Only one `Command` is ever created, so its size does not matter.
+#[allow(clippy::large_enum_variant)]
 pub enum Command {
     /// Build all outputs of a flake
     Build(BuildConfig),
 
     /// Print the Github Actions matrix configuration as JSON
+    ///
+    /// Same as `nixci matrix --format github`.
     #[clap(name = "gh-matrix")]
-    DumpGithubActionsMatrix {
+    DumpGithubActionsMatrix(MatrixConfig),
+
+    /// Print a GitLab CI child pipeline configuration as YAML
+    ///
+    /// Same as `nixci matrix --format gitlab`.
+    #[clap(name = "gitlab-pipeline")]
+    GitLabPipeline(MatrixConfig),
+
+    /// Print the CI matrix configuration for the given CI system
+    Matrix {
+        #[command(flatten)]
+        matrix_cfg: MatrixConfig,
+
+        /// The CI system to generate the matrix for
+        #[arg(long, value_enum, default_value_t = MatrixFormat::Github)]
+        format: MatrixFormat,
+    },
+
+    /// List the nixci configurations of a flake, and their sub-flakes
+    List {
         /// Flake URL or github URL
         ///
-        /// A specific nixci configuration can be specified
-        /// using '#': e.g. `nixci .#extra-tests`
+        /// If a configuration is specified using '#' (eg: `.#extra-tests`),
+        /// only that configuration is listed.
         #[arg(default_value = ".")]
         flake_ref: FlakeRef,
 
-        /// Systems to include in the matrix
-        #[arg(long, value_parser, value_delimiter = ',')]
-        systems: Vec<System>,
+        /// Print the configurations as JSON, instead of a table
+        #[arg(long)]
+        json: bool,
     },
 
     /// Generates shell completion scripts
+    ///
+    /// For bash, zsh and fish, the script also completes the configurations
+    /// and sub-flakes of local flakes (eg: `.#default.<TAB>`), and systems.
     Completion {
         #[arg(value_enum)]
         shell: clap_complete::Shell,
     },
+
+    /// Print the completion candidates for the last of the given words
+    ///
+    /// Used by the scripts generated by `nixci completion`.
+    #[command(name = "__complete", hide = true)]
+    Complete {
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        words: Vec<String>,
+    },
 }
 
 impl Command {
@@ -127,23 +183,102 @@ impl Command {
     }
 }
 
+#[derive(Parser, Debug)]
+pub struct MatrixConfig {
+    /// Flake URL or github URL
+    ///
+    /// A specific nixci configuration can be specified
+    /// using '#': e.g. `nixci .#extra-tests`, along with the sub-flakes to
+    /// include (see `nixci build --help`)
+    #[arg(default_value = ".")]
+    pub flake_ref: FlakeRef,
+
+    /// Sub-flakes not to build (names or globs, eg: `example-*`)
+    #[arg(
+        long = "exclude-subflake",
+        value_delimiter = ',',
+        value_name = "PATTERN"
+    )]
+    pub exclude_subflakes: Vec<String>,
+
+    /// Systems to include in the matrix
+    ///
+    /// Each value is either a system name (eg: `x86_64-linux`), or a flake
+    /// reference which, when imported, returns a Nix list of systems (eg:
+    /// `github:nix-systems/default`). If empty, the union of the `systems`
+    /// declared by the sub-flakes is used.
+    #[arg(long, value_delimiter = ',')]
+    pub systems: Vec<SystemOrList>,
+
+    /// Runner label to use for a system, as `SYSTEM=LABEL`
+    ///
+    /// Systems without a label use the system name itself as the label. A
+    /// sub-flake's `runsOn` attribute takes precedence over this.
+    #[arg(long, value_delimiter = ',', value_name = "SYSTEM=LABEL")]
+    pub runs_on: Vec<RunnerLabel>,
+}
+
+impl MatrixConfig {
+    /// Get the systems to include in the matrix for the given [config::Config]
+    pub async fn get_systems(&self, cmd: &NixCmd, cfg: &config::Config) -> Result<Vec<System>> {
+        let mut systems = vec![];
+        if self.systems.is_empty() {
+            systems = cfg.subflakes.declared_systems();
+            if systems.is_empty() {
+                anyhow::bail!(
+                    "No --systems given, and no sub-flake in nixci configuration '{}' declares `systems`",
+                    cfg.name
+                );
+            }
+        } else {
+            for value in &self.systems {
+                for system in value.systems(cmd).await? {
+                    if !systems.contains(&system) {
+                        systems.push(system);
+                    }
+                }
+            }
+        }
+        Ok(systems)
+    }
+}
+
 #[derive(Parser, Debug)]
 pub struct BuildConfig {
     /// The systems list to build for. If empty, build for current system.
     ///
     /// Must be a flake reference which, when imported, must return a Nix list
     /// of systems. You may use one of the lists from
-    /// https://github.com/nix-systems.
+    /// https://github.com/nix-systems, or a single system name (eg:
+    /// `riscv64-linux`).
     #[arg(long, default_value = "github:nix-systems/empty")]
     pub systems: SystemsListFlakeRef,
 
     /// Flake URL or github URL
     ///
     /// A specific nixci` configuration can be specified
-    /// using '#': e.g. `nixci .#extra-tests`
+    /// using '#': e.g. `nixci .#extra-tests`, along with the sub-flakes to
+    /// build: e.g. `.#default.dev`, `'.#default.{dev,test}'` or
+    /// `'.#default.example-*'`. Use `'.#*'` to build all configurations
+    /// (same as `--all-configs`).
     #[arg(default_value = ".")]
     pub flake_ref: FlakeRef,
 
+    /// Build all the nixci configurations of the flake
+    ///
+    /// Sub-flakes with identical definitions across configurations are built
+    /// only once.
+    #[arg(long)]
+    pub all_configs: bool,
+
+    /// Sub-flakes not to build (names or globs, eg: `example-*`)
+    #[arg(
+        long = "exclude-subflake",
+        value_delimiter = ',',
+        value_name = "PATTERN"
+    )]
+    pub exclude_subflakes: Vec<String>,
+
     /// Additional arguments to pass through to `nix build`
     #[arg(last = true, default_values_t = vec![
     "--refresh".to_string(),
@@ -158,9 +293,146 @@ pub struct BuildConfig {
     /// useful to explicitly push all dependencies to a cache.
     #[clap(long, short = 'd')]
     pub print_all_dependencies: bool,
+
+    /// Which dependencies `--print-all-dependencies` includes
+    ///
+    /// Use `runtime` when pushing to a public cache, and `build` to warm the
+    /// cache of a builder.
+    #[arg(long, value_enum, default_value_t = ClosureKind::All)]
+    pub closure: ClosureKind,
+
+    /// Print the runtime closure size of each built output, largest first
+    #[arg(long)]
+    pub closure_report: bool,
+
+    /// Compare closure sizes against this baseline file, failing if any grew
+    /// by more than `--closure-threshold` (implies `--closure-report`)
+    ///
+    /// The baseline is written by `--write-closure-baseline`, and is meant
+    /// to be committed to the repository. Outputs of the baseline that were
+    /// not built are warned about.
+    #[arg(long, value_name = "FILE")]
+    pub closure_baseline: Option<PathBuf>,
+
+    /// Maximum growth, in percent, of a closure over `--closure-baseline`
+    #[arg(long, value_name = "PERCENT", default_value_t = 10.0)]
+    pub closure_threshold: f64,
+
+    /// Write the closure sizes to this file, for use as `--closure-baseline`
+    /// (implies `--closure-report`)
+    #[arg(long, value_name = "FILE")]
+    pub write_closure_baseline: Option<PathBuf>,
+
+    /// How to query the Nix store for `--print-all-dependencies` and `--closure-report`
+    #[arg(long, value_enum, default_value_t = StoreBackend::Legacy)]
+    pub store_backend: StoreBackend,
+
+    /// Copy the outputs of each sub-flake to this store after building it
+    ///
+    /// Any store URI accepted by `nix copy --to` can be used, eg:
+    /// `file:///tmp/cache` or `s3://bucket`. With `--print-all-dependencies`,
+    /// the dependencies selected by `--closure` are copied as well.
+    #[arg(long, value_name = "STORE_URI")]
+    pub push_to: Option<String>,
+
+    /// Skip sub-flakes whose outputs are all available in a substituter
+    ///
+    /// The output paths are evaluated (without building) and looked up in the
+    /// substituters first. Sub-flakes with any output missing, or whose outputs
+    /// cannot be evaluated this way, are built as usual.
+    #[arg(long)]
+    pub skip_cached: bool,
+
+    /// The substituters to look up outputs in, with `--skip-cached`
+    ///
+    /// Defaults to the `substituters` of the Nix configuration. Any store URI
+    /// accepted by `nix path-info --store` can be used, eg: `file:///tmp/cache`.
+    #[arg(long = "substituter", value_name = "STORE_URI", value_delimiter = ',')]
+    pub substituters: Vec<String>,
+
+    /// Build sub-flakes whose `systems` are not being built for on the
+    /// matching remote builders listed in this file
+    ///
+    /// The file is in the format of Nix's machines file (eg:
+    /// `ssh://mac-mini aarch64-darwin` per line). Sub-flakes may also declare
+    /// their own `builders`.
+    #[arg(long, value_name = "FILE")]
+    pub builders_file: Option<PathBuf>,
+
+    /// Keep building the remaining sub-flakes if one of them fails
+    ///
+    /// A pass/fail summary is printed at the end, and nixci exits with a
+    /// non-zero code if any sub-flake failed.
+    #[arg(long)]
+    pub keep_going: bool,
+
+    /// Number of sub-flakes to build concurrently
+    ///
+    /// When building more than one sub-flake at a time, each line of the
+    /// Nix log (of `nix flake lock`, and of the build) is prefixed with the
+    /// name of the sub-flake it belongs to.
+    #[arg(long, default_value = "1")]
+    pub jobs: NonZeroUsize,
+
+    /// Build only these categories of flake outputs
+    ///
+    /// If a sub-flake also restricts its outputs (`includeOutputs`), only
+    /// the categories selected by both are built.
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub include_outputs: Vec<OutputCategory>,
+
+    /// Do not build these categories of flake outputs
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub exclude_outputs: Vec<OutputCategory>,
+
+    /// Print a JSON report of the build, instead of the out paths
+    ///
+    /// The report contains an entry for each sub-flake, with its
+    /// configuration, built outputs and build status.
+    #[arg(long)]
+    pub json: bool,
+
+    /// Write a JSON report of the build to the given file
+    ///
+    /// Unlike `--json`, this does not change what is printed to stdout.
+    #[arg(long, value_name = "FILE")]
+    pub report: Option<PathBuf>,
 }
 
 impl BuildConfig {
+    /// Get the nixci configurations to build
+    ///
+    /// This is the configuration in [BuildConfig::flake_ref], or all of them
+    /// with [BuildConfig::all_configs] (or `.#*`).
+    pub async fn get_configs(&self, cmd: &NixCmd) -> Result<Vec<config::Config>> {
+        let url = self.flake_ref.to_flake_url().await?;
+        let all_configs = match url.split_attr().1.as_list().as_slice() {
+            [] => self.all_configs,
+            [attr] if attr == "*" => true,
+            _ if self.all_configs => {
+                anyhow::bail!("--all-configs cannot be used along with a configuration name in the flake URL: {}", url.0)
+            }
+            _ => false,
+        };
+        tracing::info!("{}", format!("🍏 {}", url.0).bold());
+        let cfgs = if all_configs {
+            let mut cfgs = config::Config::all_from_flake_url(cmd, &url).await?;
+            config::exclude_subflakes(&mut cfgs, &self.exclude_subflakes)?;
+            cfgs
+        } else {
+            let mut cfg = config::Config::from_flake_url(cmd, &url).await?;
+            cfg.exclude_subflakes(&self.exclude_subflakes)?;
+            vec![cfg]
+        };
+        tracing::debug!("Configs: {cfgs:?}");
+        Ok(cfgs)
+    }
+
+    /// The outputs to build, as selected on the command line
+    pub fn output_filter(&self) -> OutputFilter {
+        OutputFilter::new(&self.include_outputs, &self.exclude_outputs)
+    }
+
     pub async fn get_systems(&self, cmd: &NixCmd, nix_config: &NixConfig) -> Result<Vec<System>> {
         let systems = SystemsList::from_flake(cmd, &self.systems).await?.0;
         if systems.is_empty() {
@@ -170,6 +442,27 @@ impl BuildConfig {
             Ok(systems)
         }
     }
+
+    /// Whether closure sizes are to be computed
+    pub fn closure_report_enabled(&self) -> bool {
+        self.closure_report
+            || self.closure_baseline.is_some()
+            || self.write_closure_baseline.is_some()
+    }
+
+    /// The substituters to look up outputs in, with [BuildConfig::skip_cached]
+    pub fn get_substituters(&self, nix_config: &NixConfig) -> Vec<String> {
+        if self.substituters.is_empty() {
+            nix_config
+                .substituters
+                .value
+                .iter()
+                .map(|url| url.to_string())
+                .collect()
+        } else {
+            self.substituters.clone()
+        }
+    }
 }
 
 #[cfg(test)]
@@ -196,6 +489,41 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_system_or_list() {
+        assert_eq!(
+            SystemOrList::from_str("riscv64-linux").unwrap(),
+            SystemOrList::System("riscv64-linux".into())
+        );
+        assert_eq!(
+            SystemOrList::from_str("github:nix-systems/default").unwrap(),
+            SystemOrList::List(SystemsListFlakeRef(FlakeUrl(
+                "github:nix-systems/default".to_string()
+            )))
+        );
+        assert_eq!(
+            SystemOrList::from_str("./systems").unwrap(),
+            SystemOrList::List(SystemsListFlakeRef(FlakeUrl("./systems".to_string())))
+        );
+    }
+
+    #[test]
+    fn test_matrix_systems() {
+        let args =
+            CliArgs::try_parse_from(["nixci", "matrix", "--systems", "x86_64-linux,riscv64-linux"])
+                .unwrap();
+        let Command::Matrix { matrix_cfg, .. } = args.command else {
+            panic!("Expected the matrix subcommand");
+        };
+        assert_eq!(
+            matrix_cfg.systems,
+            vec![
+                SystemOrList::System("x86_64-linux".into()),
+                SystemOrList::System("riscv64-linux".into()),
+            ]
+        );
+    }
+
     #[test]
     fn test_flake_url() {
         assert_eq!(
@@ -0,0 +1,210 @@
+//! Closure sizes of built outputs, and their comparison against a baseline
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::{bail, Context, Result};
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+
+/// The runtime closure sizes of built outputs
+///
+/// Entries are keyed by `<config>.<subflake>#<attr>` (eg:
+/// `default.dev#packages.x86_64-linux.foo`), so that they remain comparable
+/// across builds. This is also the format of `--closure-baseline` files.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClosureReport {
+    /// Closure size, in bytes, of each output
+    pub sizes: BTreeMap<String, u64>,
+}
+
+/// An output whose closure grew beyond the threshold over the baseline
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClosureRegression {
+    pub key: String,
+    pub baseline: u64,
+    pub current: u64,
+}
+
+impl ClosureRegression {
+    /// The growth of the closure over the baseline, in percent
+    pub fn growth(&self) -> f64 {
+        growth(self.baseline, self.current)
+    }
+}
+
+fn growth(baseline: u64, current: u64) -> f64 {
+    if baseline == 0 {
+        if current == 0 {
+            0.0
+        } else {
+            f64::INFINITY
+        }
+    } else {
+        (current as f64 - baseline as f64) * 100.0 / baseline as f64
+    }
+}
+
+impl ClosureReport {
+    /// Read a report previously written with [ClosureReport::write_to]
+    pub fn read_from(path: &Path) -> Result<ClosureReport> {
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("Unable to read closure baseline {}", path.display()))?;
+        serde_json::from_str(&json)
+            .with_context(|| format!("Invalid closure baseline {}", path.display()))
+    }
+
+    /// Write the report as JSON to the given file
+    pub fn write_to(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, json + "\n")
+            .with_context(|| format!("Unable to write closure sizes to {}", path.display()))
+    }
+
+    /// Add the closure size of the output with the given key
+    ///
+    /// Fails if the key is already present, as the sizes of distinct outputs
+    /// would then be mixed up (eg: same-named outputs of different systems,
+    /// if their flake attributes are not known).
+    pub fn insert(&mut self, key: String, size: u64) -> Result<()> {
+        if self.sizes.contains_key(&key) {
+            bail!(
+                "More than one output has the closure report key {}, so their sizes cannot be told apart",
+                key
+            );
+        }
+        self.sizes.insert(key, size);
+        Ok(())
+    }
+
+    /// Return the keys of `baseline` that are missing from this report
+    pub fn missing<'a>(&self, baseline: &'a ClosureReport) -> Vec<&'a String> {
+        baseline
+            .sizes
+            .keys()
+            .filter(|key| !self.sizes.contains_key(*key))
+            .collect()
+    }
+
+    /// Return the outputs whose closure grew by more than `threshold` percent over `baseline`
+    ///
+    /// Outputs new to this report cannot regress, and those missing from it
+    /// (see [ClosureReport::missing]) are not compared.
+    pub fn regressions(&self, baseline: &ClosureReport, threshold: f64) -> Vec<ClosureRegression> {
+        self.sizes
+            .iter()
+            .filter_map(|(key, &current)| {
+                let &baseline = baseline.sizes.get(key)?;
+                (growth(baseline, current) > threshold).then(|| ClosureRegression {
+                    key: key.clone(),
+                    baseline,
+                    current,
+                })
+            })
+            .collect()
+    }
+
+    /// Log a table of closure sizes, largest first, along with the change
+    /// over `baseline` if given
+    ///
+    /// Outputs of `baseline` missing from this report are warned about, as
+    /// their closures can no longer be checked.
+    pub fn print(&self, baseline: Option<&ClosureReport>) {
+        let mut entries: Vec<(&String, &u64)> = self.sizes.iter().collect();
+        entries.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        let width = entries.iter().map(|(k, _)| k.len()).max().unwrap_or(0);
+        tracing::info!("{}", "📏 Closure sizes".bold());
+        for (key, &size) in entries {
+            let change = match baseline.and_then(|b| b.sizes.get(key)) {
+                Some(&before) => format!("({:+.1}%)", growth(before, size)),
+                None if baseline.is_some() => "(new)".to_string(),
+                None => String::new(),
+            };
+            tracing::info!(
+                "   {:width$}  {:>10}  {}",
+                key,
+                format_size(size),
+                change.dimmed()
+            );
+        }
+        for key in baseline.map_or(vec![], |b| self.missing(b)) {
+            tracing::warn!(
+                "⚠️  {} {}",
+                key,
+                "is in the closure baseline, but was not built".dimmed()
+            );
+        }
+    }
+}
+
+/// Format a size in bytes using binary units, eg: `1.5 MiB`
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(sizes: &[(&str, u64)]) -> ClosureReport {
+        ClosureReport {
+            sizes: sizes.iter().map(|(k, v)| (k.to_string(), *v)).collect(),
+        }
+    }
+
+    #[test]
+    fn test_regressions() {
+        let baseline = report(&[("default.dev#foo", 1000), ("default.dev#bar", 1000)]);
+        let current = report(&[
+            ("default.dev#foo", 1050),
+            ("default.dev#bar", 2000),
+            ("default.dev#new", 5000),
+        ]);
+        assert_eq!(
+            current.regressions(&baseline, 10.0),
+            vec![ClosureRegression {
+                key: "default.dev#bar".to_string(),
+                baseline: 1000,
+                current: 2000,
+            }]
+        );
+        assert_eq!(current.regressions(&baseline, 4.0).len(), 2);
+        assert_eq!(current.regressions(&baseline, 100.0), vec![]);
+    }
+
+    #[test]
+    fn test_insert() -> Result<()> {
+        let mut closures = ClosureReport::default();
+        closures.insert("default.dev#foo".to_string(), 1000)?;
+        closures.insert("default.dev#bar".to_string(), 1000)?;
+        assert!(closures
+            .insert("default.dev#foo".to_string(), 2000)
+            .is_err());
+        assert_eq!(closures.sizes["default.dev#foo"], 1000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing() {
+        let baseline = report(&[("default.dev#foo", 1000), ("default.dev#gone", 1000)]);
+        let current = report(&[("default.dev#foo", 1000), ("default.dev#new", 1000)]);
+        assert_eq!(current.missing(&baseline), vec!["default.dev#gone"]);
+        assert!(baseline.missing(&baseline).is_empty());
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+}
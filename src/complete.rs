@@ -0,0 +1,316 @@
+//! Dynamic shell completion of configurations, sub-flakes and systems
+//!
+//! The scripts generated by `nixci completion <shell>` call the hidden
+//! `nixci __complete <words>..` subcommand to complete the words that static
+//! completion cannot know about: `<flake>#<config>.<subflake>` flake
+//! references, and `--systems` values.
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap},
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use clap::{Command, CommandFactory};
+use clap_complete::Shell;
+use nix_rs::{command::NixCmd, flake::url::FlakeUrl};
+
+use crate::{cli::CliArgs, config::Config, nix::system_list::KNOWN_NIX_SYSTEMS};
+
+/// Configuration names of a flake, along with the names of their sub-flakes
+pub type ConfigNames = BTreeMap<String, Vec<String>>;
+
+/// Return the completion candidates for the last of the given command-line
+/// `words` (excluding the program name)
+///
+/// Only local flakes are evaluated, and their configuration names are cached
+/// (see [cache_path]) so that completion remains fast.
+pub async fn complete(cmd: &NixCmd, words: &[String]) -> Result<Vec<String>> {
+    let current = words.last().map(String::as_str).unwrap_or("");
+    let previous = words.len().checked_sub(2).map(|i| words[i].as_str());
+    if previous == Some("--systems") {
+        return Ok(complete_systems(current));
+    }
+    if let Some(systems) = current.strip_prefix("--systems=") {
+        return Ok(complete_systems(systems)
+            .into_iter()
+            .map(|s| format!("--systems={}", s))
+            .collect());
+    }
+    let Some((flake, _)) = current.split_once('#') else {
+        return Ok(vec![]);
+    };
+    let Some(dir) = local_flake_dir(flake) else {
+        return Ok(vec![]);
+    };
+    let names = config_names(cmd, &dir).await?;
+    Ok(complete_flake_attr(flake, &names, current))
+}
+
+/// Complete the last of the comma-separated systems in `current`
+fn complete_systems(current: &str) -> Vec<String> {
+    let (done, partial) = match current.rsplit_once(',') {
+        Some((done, partial)) => (format!("{},", done), partial),
+        None => (String::new(), current),
+    };
+    KNOWN_NIX_SYSTEMS
+        .iter()
+        .filter(|system| system.starts_with(partial))
+        .map(|system| format!("{}{}", done, system))
+        .collect()
+}
+
+/// Complete `current` to `<flake>#<config>` or `<flake>#<config>.<subflake>`
+fn complete_flake_attr(flake: &str, names: &ConfigNames, current: &str) -> Vec<String> {
+    names
+        .iter()
+        .flat_map(|(config, subflakes)| {
+            std::iter::once(format!("{}#{}", flake, config)).chain(
+                subflakes
+                    .iter()
+                    .map(move |subflake| format!("{}#{}.{}", flake, config, subflake)),
+            )
+        })
+        .filter(|candidate| candidate.starts_with(current))
+        .collect()
+}
+
+/// The directory of a local flake reference, or `None` for other flakes
+/// (which are too slow to fetch during completion)
+fn local_flake_dir(flake: &str) -> Option<PathBuf> {
+    let path = flake
+        .strip_prefix("path:")
+        .or_else(|| flake.strip_prefix("git+file://"))
+        .unwrap_or(flake);
+    let path = path.split('?').next().unwrap_or(path);
+    if path.is_empty() || path.contains(':') {
+        None
+    } else {
+        Some(PathBuf::from(path))
+    }
+}
+
+/// Evaluate the configuration names of the flake in `dir`, or read them from
+/// the cache
+async fn config_names(cmd: &NixCmd, dir: &Path) -> Result<ConfigNames> {
+    let dir = dir
+        .canonicalize()
+        .with_context(|| format!("No flake at {}", dir.display()))?;
+    let url = FlakeUrl(dir.to_string_lossy().to_string());
+    // Enable flakes unconditionally, as `nix show-config` would slow down completion
+    let mut cmd = cmd.clone();
+    if cmd.extra_experimental_features.is_empty() {
+        cmd.extra_experimental_features = NixCmd::default().extra_experimental_features;
+    }
+    let cache = cache_path(&dir, flake_fingerprint(&dir)?)?;
+    if let Some(names) = std::fs::read_to_string(&cache)
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+    {
+        return Ok(names);
+    }
+    let names: ConfigNames = Config::all_from_flake_url(&cmd, &url)
+        .await?
+        .into_iter()
+        .map(|cfg| (cfg.name, cfg.subflakes.0.into_keys().collect()))
+        .collect();
+    if let Err(err) = write_cache(&cache, &names) {
+        tracing::debug!("Unable to cache completions: {:#}", err);
+    }
+    Ok(names)
+}
+
+fn write_cache(cache: &Path, names: &ConfigNames) -> Result<()> {
+    std::fs::write(cache, serde_json::to_string(names)?)?;
+    Ok(())
+}
+
+/// A hash of the `flake.nix` and `flake.lock` files (and their modification
+/// times) of the flake in `dir`
+///
+/// This is read directly, as calling Nix on every completion would be too
+/// slow. Changes to other files of the flake are not noticed.
+fn flake_fingerprint(dir: &Path) -> Result<u64> {
+    let mut hasher = DefaultHasher::new();
+    for name in ["flake.nix", "flake.lock"] {
+        let path = dir.join(name);
+        match std::fs::read(&path) {
+            Ok(contents) => {
+                let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+                (name, contents, modified).hash(&mut hasher);
+            }
+            // A flake need not be locked yet
+            Err(err) if name == "flake.lock" && err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => {
+                return Err(err).with_context(|| format!("Unable to read {}", path.display()))
+            }
+        }
+    }
+    Ok(hasher.finish())
+}
+
+/// The cache file of the configuration names of the flake in `dir`, whose
+/// files have the given fingerprint (see [flake_fingerprint])
+fn cache_path(dir: &Path, fingerprint: u64) -> Result<PathBuf> {
+    let mut hasher = DefaultHasher::new();
+    (dir, fingerprint).hash(&mut hasher);
+    Ok(crate::cache::cache_dir("completion")?.join(format!("{:016x}.json", hasher.finish())))
+}
+
+/// The nixci command without its hidden subcommands (like `__complete`), to
+/// generate the static completion script from
+pub fn visible_command() -> Command {
+    let cli = CliArgs::command();
+    Command::new(env!("CARGO_PKG_NAME"))
+        .version(env!("CARGO_PKG_VERSION"))
+        .args(cli.get_arguments().cloned())
+        .subcommands(
+            cli.get_subcommands()
+                .filter(|sc| !sc.is_hide_set())
+                .cloned(),
+        )
+}
+
+/// Shell code that hooks `nixci __complete` into the static completion
+/// script generated by clap for `shell`
+///
+/// The static script is used as is for shells not supported here.
+pub fn dynamic_hook(shell: Shell, name: &str) -> Option<String> {
+    let script = match shell {
+        Shell::Bash => BASH_HOOK,
+        Shell::Zsh => ZSH_HOOK,
+        Shell::Fish => FISH_HOOK,
+        _ => return None,
+    };
+    Some(script.replace("__NIXCI__", name))
+}
+
+const BASH_HOOK: &str = r##"
+___NIXCI___dynamic() {
+    # Split the line ourselves, as COMP_WORDS is also split at ':' and '='
+    local line="${COMP_LINE:0:COMP_POINT}" words
+    read -ra words <<< "$line"
+    [[ "$line" =~ [[:space:]]$ ]] && words+=("")
+    local word="${words[-1]}" previous="${words[-2]}"
+    if [[ "$word" == *"#"* || "$word" == --systems=* || "$previous" == "--systems" ]]; then
+        local cur="${COMP_WORDS[COMP_CWORD]}" candidate
+        COMPREPLY=()
+        while IFS= read -r candidate; do
+            COMPREPLY+=("${candidate#"${word%"$cur"}"}")
+        done < <(__NIXCI__ __complete "${words[@]:1}" 2>/dev/null)
+    else
+        ___NIXCI__ "$@"
+    fi
+}
+complete -F ___NIXCI___dynamic -o nosort -o bashdefault -o default __NIXCI__
+"##;
+
+const ZSH_HOOK: &str = r##"
+___NIXCI___dynamic() {
+    local word="${words[CURRENT]}" previous="${words[CURRENT-1]}"
+    if [[ "$word" == *"#"* || "$word" == --systems=* || "$previous" == "--systems" ]]; then
+        local -a candidates
+        candidates=(${(f)"$(__NIXCI__ __complete "${(@)words[2,CURRENT]}" 2>/dev/null)"})
+        compadd -Q -- "${candidates[@]}"
+    else
+        ___NIXCI__ "$@"
+    fi
+}
+compdef ___NIXCI___dynamic __NIXCI__
+"##;
+
+const FISH_HOOK: &str = r##"
+complete -c __NIXCI__ -f -n 'string match -q -- "*#*" (commandline -ct)' -a '(__NIXCI__ __complete (commandline -opc)[2..-1] (commandline -ct) 2>/dev/null)'
+complete -c __NIXCI__ -f -n 'test "$(commandline -opc)[-1]" = --systems' -a '(__NIXCI__ __complete (commandline -opc)[2..-1] (commandline -ct) 2>/dev/null)'
+"##;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_complete_systems() {
+        assert_eq!(
+            complete_systems("aarch64"),
+            vec!["aarch64-darwin", "aarch64-linux"]
+        );
+        assert_eq!(
+            complete_systems("x86_64-linux,aarch64-d"),
+            vec!["x86_64-linux,aarch64-darwin"]
+        );
+        assert_eq!(complete_systems("").len(), KNOWN_NIX_SYSTEMS.len());
+    }
+
+    #[test]
+    fn test_complete_flake_attr() {
+        let names: ConfigNames = [
+            ("default", vec!["dev", "doc"]),
+            ("extra-tests", vec!["test"]),
+        ]
+        .into_iter()
+        .map(|(c, s)| (c.to_string(), s.into_iter().map(String::from).collect()))
+        .collect();
+        assert_eq!(
+            complete_flake_attr(".", &names, ".#"),
+            vec![
+                ".#default",
+                ".#default.dev",
+                ".#default.doc",
+                ".#extra-tests",
+                ".#extra-tests.test"
+            ]
+        );
+        assert_eq!(
+            complete_flake_attr(".", &names, ".#default.d"),
+            vec![".#default.dev", ".#default.doc"]
+        );
+        assert_eq!(
+            complete_flake_attr("./foo", &names, "./foo#ex"),
+            vec!["./foo#extra-tests", "./foo#extra-tests.test"]
+        );
+    }
+
+    #[test]
+    fn test_local_flake_dir() {
+        assert_eq!(local_flake_dir("."), Some(PathBuf::from(".")));
+        assert_eq!(local_flake_dir("path:./foo"), Some(PathBuf::from("./foo")));
+        assert_eq!(
+            local_flake_dir("git+file:///code/foo?ref=main"),
+            Some(PathBuf::from("/code/foo"))
+        );
+        assert_eq!(local_flake_dir("github:srid/nixci"), None);
+    }
+
+    #[test]
+    fn test_visible_command() {
+        let cli = visible_command();
+        assert!(cli.find_subcommand("build").is_some());
+        assert!(cli.find_subcommand("__complete").is_none());
+        assert!(cli.get_arguments().any(|arg| arg.get_id() == "store"));
+    }
+
+    #[test]
+    fn test_cache_path() -> Result<()> {
+        let dir = Path::new("/code/foo");
+        let path = cache_path(dir, 1)?;
+        assert_eq!(path, cache_path(dir, 1)?);
+        assert_ne!(path, cache_path(dir, 2)?);
+        assert_ne!(path, cache_path(Path::new("/code/bar"), 1)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_flake_fingerprint() -> Result<()> {
+        let dir =
+            std::env::temp_dir().join(format!("nixci-test-fingerprint-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        assert!(flake_fingerprint(&dir).is_err());
+        std::fs::write(dir.join("flake.nix"), "{ outputs = _: { }; }")?;
+        let unlocked = flake_fingerprint(&dir)?;
+        assert_eq!(unlocked, flake_fingerprint(&dir)?);
+        std::fs::write(dir.join("flake.lock"), "{}")?;
+        assert_ne!(unlocked, flake_fingerprint(&dir)?);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
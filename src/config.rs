@@ -1,13 +1,23 @@
-use std::collections::BTreeMap;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    str::FromStr,
+};
 
 use anyhow::Result;
 use nix_rs::{
     command::NixCmd,
     flake::{eval::nix_eval_attr_json, system::System, url::FlakeUrl},
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-use crate::cli::BuildConfig;
+use crate::{
+    cli::BuildConfig,
+    nix::{
+        builders::Builder,
+        devour_flake::{self, OutputCategory, OutputFilter},
+        system_list::SystemsListFlakeRef,
+    },
+};
 
 /// The `nixci` configuration encoded in flake.nix
 ///
@@ -32,10 +42,8 @@ pub struct Config {
     /// Configuration name (nixci.<name>)
     pub name: String,
 
-    /// Selected sub-flake if any.
-    ///
-    /// Must be a key in `subflakes`.
-    pub selected_subflake: Option<String>,
+    /// The sub-flakes selected to build
+    pub selection: SubflakeSelection,
 }
 
 impl Config {
@@ -49,37 +57,192 @@ impl Config {
     /// along with the config.
     pub async fn from_flake_url(cmd: &NixCmd, url: &FlakeUrl) -> Result<Config> {
         let (flake_url, attr) = url.split_attr();
-        let nested_attr = attr.as_list();
-        let (name, selected_subflake) = match nested_attr.as_slice() {
-            [] => ("default".to_string(), None),
-            [name] => (name.clone(), None),
-            [name, sub_flake] => (name.clone(), Some(sub_flake.to_string())),
-            _ => anyhow::bail!("Invalid flake URL (too many nested attr): {}", flake_url.0),
+        let (name, selection) = match url.0.split_once('#').map(|(_, attr)| attr) {
+            None => ("default".to_string(), SubflakeSelection::default()),
+            Some(attr) => match attr.split_once('.') {
+                None => (attr.to_string(), SubflakeSelection::default()),
+                Some((_, subflakes)) if subflakes.contains('.') => {
+                    anyhow::bail!("Invalid flake URL (too many nested attr): {}", url.0)
+                }
+                Some((name, subflakes)) => (name.to_string(), SubflakeSelection::parse(subflakes)?),
+            },
         };
         let nixci_url = FlakeUrl(format!("{}#nixci.{}", flake_url.0, name));
         let subflakes = nix_eval_attr_json::<Subflakes>(cmd, &nixci_url, attr.is_none()).await?;
-        if let Some(sub_flake_name) = selected_subflake.clone() {
-            if !subflakes.0.contains_key(&sub_flake_name) {
-                anyhow::bail!(
-                    "Sub-flake '{}' not found in nixci configuration '{}'",
-                    sub_flake_name,
-                    nixci_url
-                )
-            }
-        }
         let cfg = Config {
             subflakes,
             flake_url,
             name,
-            selected_subflake,
+            selection,
         };
+        cfg.selection.check(&cfg.subflakes, &cfg.name)?;
         Ok(cfg)
     }
+
+    /// Deselect the sub-flakes matching any of the given patterns (see [SubflakeSelection])
+    pub fn exclude_subflakes(&mut self, patterns: &[String]) -> Result<()> {
+        self.selection.exclude.extend(patterns.iter().cloned());
+        self.selection.check(&self.subflakes, &self.name)
+    }
+
+    /// Whether the given sub-flake is selected to build
+    pub fn is_selected(&self, subflake_name: &str) -> bool {
+        self.selection.matches(subflake_name)
+    }
+
+    /// Create a `Config` for each `nixci.<name>` configuration of the flake pointed to by this [FlakeUrl]
+    ///
+    /// The attribute part of the URL, if any, is ignored. If the flake has no
+    /// `nixci` output, the default configuration is returned.
+    pub async fn all_from_flake_url(cmd: &NixCmd, url: &FlakeUrl) -> Result<Vec<Config>> {
+        let (flake_url, _) = url.split_attr();
+        let nixci_url = FlakeUrl(format!("{}#nixci", flake_url.0));
+        let mut configs =
+            nix_eval_attr_json::<BTreeMap<String, Subflakes>>(cmd, &nixci_url, true).await?;
+        if configs.is_empty() {
+            configs.insert("default".to_string(), Subflakes::default());
+        }
+        Ok(configs
+            .into_iter()
+            .map(|(name, subflakes)| Config {
+                subflakes,
+                flake_url: flake_url.clone(),
+                name,
+                selection: SubflakeSelection::default(),
+            })
+            .collect())
+    }
 }
 
-#[derive(Debug, Deserialize)]
+#[cfg(test)]
+impl Config {
+    /// A configuration of the flake at `.`, with the given sub-flakes all selected
+    pub(crate) fn for_test(name: &str, subflakes: Vec<(&str, SubFlakish)>) -> Config {
+        Config {
+            subflakes: Subflakes(
+                subflakes
+                    .into_iter()
+                    .map(|(k, v)| (k.to_string(), v))
+                    .collect(),
+            ),
+            flake_url: FlakeUrl(".".to_string()),
+            name: name.to_string(),
+            selection: SubflakeSelection::default(),
+        }
+    }
+}
+
+/// Deselect the sub-flakes matching any of the given patterns in each of `configs`
+///
+/// Unlike [Config::exclude_subflakes], a pattern need only match a sub-flake
+/// of one of the configurations.
+pub fn exclude_subflakes(configs: &mut [Config], patterns: &[String]) -> Result<()> {
+    for pattern in patterns {
+        let matching = |cfg: &Config| cfg.subflakes.0.keys().any(|n| glob_match(pattern, n));
+        if !configs.iter().any(matching) {
+            let available: Vec<String> = configs
+                .iter()
+                .flat_map(|cfg| {
+                    cfg.subflakes
+                        .0
+                        .keys()
+                        .map(|n| format!("{}.{}", cfg.name, n))
+                })
+                .collect();
+            anyhow::bail!(
+                "No sub-flake matching '{}' in any nixci configuration (available: {})",
+                pattern,
+                available.join(", ")
+            );
+        }
+    }
+    for cfg in configs {
+        cfg.selection.exclude.extend(patterns.iter().cloned());
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Subflakes(pub BTreeMap<String, SubFlakish>);
 
+impl Subflakes {
+    /// The union of the `systems` whitelists of all sub-flakes, in order of appearance
+    pub fn declared_systems(&self) -> Vec<System> {
+        let mut systems: Vec<System> = vec![];
+        for system in self.0.values().flat_map(|v| v.systems.iter().flatten()) {
+            if !systems.contains(system) {
+                systems.push(system.clone());
+            }
+        }
+        systems
+    }
+}
+
+impl Subflakes {
+    /// The names of the sub-flakes in the order to build them
+    ///
+    /// Each sub-flake comes after those it depends on (`dependsOn`), and
+    /// otherwise in asciibetical order. Fails if a dependency is unknown, or
+    /// if the dependencies form a cycle.
+    pub fn build_order(&self, config_name: &str) -> Result<Vec<&str>> {
+        for (name, subflake) in &self.0 {
+            for dep in &subflake.depends_on {
+                if !self.0.contains_key(dep) {
+                    anyhow::bail!(
+                        "Sub-flake '{}' depends on unknown sub-flake '{}' in nixci configuration '{}' (available: {})",
+                        name,
+                        dep,
+                        config_name,
+                        self.0.keys().map(String::as_str).collect::<Vec<_>>().join(", ")
+                    );
+                }
+            }
+        }
+        // Kahn's algorithm, always picking the first ready sub-flake
+        let mut pending: BTreeMap<&str, BTreeSet<&str>> = self
+            .0
+            .iter()
+            .map(|(name, v)| {
+                (
+                    name.as_str(),
+                    v.depends_on.iter().map(String::as_str).collect(),
+                )
+            })
+            .collect();
+        let mut order = vec![];
+        while let Some(next) = pending
+            .iter()
+            .find(|(_, deps)| deps.is_empty())
+            .map(|(&name, _)| name)
+        {
+            pending.remove(next);
+            for deps in pending.values_mut() {
+                deps.remove(next);
+            }
+            order.push(next);
+        }
+        if let Some((&start, _)) = pending.first_key_value() {
+            // Every pending sub-flake has a pending dependency, so following
+            // them from any one of them must eventually lead to a cycle.
+            let mut path = vec![start];
+            let cycle = loop {
+                let last = path[path.len() - 1];
+                let next = *pending[last].first().expect("pending dependency");
+                if let Some(pos) = path.iter().position(|&n| n == next) {
+                    break [&path[pos..], &[next]].concat();
+                }
+                path.push(next);
+            };
+            anyhow::bail!(
+                "Dependency cycle among the sub-flakes of nixci configuration '{}': {}",
+                config_name,
+                cycle.join(" → ")
+            );
+        }
+        Ok(order)
+    }
+}
+
 impl Default for Subflakes {
     /// Default value contains a single entry for the root flake.
     fn default() -> Self {
@@ -89,11 +252,116 @@ impl Default for Subflakes {
     }
 }
 
+/// The sub-flakes of a [Config] selected to build
+///
+/// Sub-flakes are selected using patterns, which are either names or globs
+/// (`*` matches any characters, and `?` any single character).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SubflakeSelection {
+    /// Patterns of the sub-flakes to build (all of them, if empty)
+    pub include: Vec<String>,
+    /// Patterns of the sub-flakes not to build
+    pub exclude: Vec<String>,
+}
+
+impl SubflakeSelection {
+    /// Parse the sub-flake part of a flake URL attribute (eg: `dev` in
+    /// `.#default.dev`)
+    ///
+    /// A group of alternatives in braces is expanded, as in the shell: eg:
+    /// `{dev,test}` or `example-{a,b}`.
+    pub fn parse(s: &str) -> Result<SubflakeSelection> {
+        let include = match (s.find('{'), s.find('}')) {
+            (None, None) => vec![s.to_string()],
+            (Some(open), Some(close)) if open < close && !s[close + 1..].contains(['{', '}']) => {
+                let (prefix, suffix) = (&s[..open], &s[close + 1..]);
+                s[open + 1..close]
+                    .split(',')
+                    .map(|alt| format!("{}{}{}", prefix, alt, suffix))
+                    .collect()
+            }
+            _ => anyhow::bail!("Invalid sub-flake selection (unbalanced braces): {}", s),
+        };
+        Ok(SubflakeSelection {
+            include,
+            exclude: vec![],
+        })
+    }
+
+    /// Whether the given sub-flake is selected
+    pub fn matches(&self, subflake_name: &str) -> bool {
+        let matching = |patterns: &[String]| patterns.iter().any(|p| glob_match(p, subflake_name));
+        (self.include.is_empty() || matching(&self.include)) && !matching(&self.exclude)
+    }
+
+    /// Check that each pattern matches some of `subflakes`, and that some
+    /// sub-flake remains selected
+    pub fn check(&self, subflakes: &Subflakes, config_name: &str) -> Result<()> {
+        let available = || {
+            subflakes
+                .0
+                .keys()
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        for pattern in self.include.iter().chain(&self.exclude) {
+            if !subflakes.0.keys().any(|name| glob_match(pattern, name)) {
+                anyhow::bail!(
+                    "No sub-flake matching '{}' in nixci configuration '{}' (available: {})",
+                    pattern,
+                    config_name,
+                    available()
+                );
+            }
+        }
+        if !subflakes.0.keys().any(|name| self.matches(name)) {
+            anyhow::bail!(
+                "No sub-flake selected in nixci configuration '{}' (available: {})",
+                config_name,
+                available()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Match `name` against a glob `pattern` supporting `*` and `?`
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), name.chars().collect());
+    // Position of the last `*` in the pattern, and of the name when it was reached
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                // Let the last `*` match one more character
+                Some((sp, sn)) => {
+                    star = Some((sp, sn + 1));
+                    p = sp + 1;
+                    n = sn + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
 /// Represents a sub-flake look-alike.
 ///
 /// "Look-alike" because its inputs may be partial, thus requiring explicit
 /// --override-inputs when evaluating the flake.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SubFlakish {
     /// Subdirectory in which the flake lives
     pub dir: String,
@@ -106,6 +374,34 @@ pub struct SubFlakish {
 
     /// An optional whitelist of systems to build on (others are ignored)
     pub systems: Option<Vec<System>>,
+
+    /// Extra arguments to pass to `nix build`, after those passed on the command line
+    ///
+    /// Useful for options only needed by this sub-flake, eg: `--impure`.
+    #[serde(rename = "extraArgs", default)]
+    pub extra_args: Vec<String>,
+
+    /// Output categories to build (all, if empty)
+    #[serde(rename = "includeOutputs", default)]
+    pub include_outputs: Vec<OutputCategory>,
+
+    /// Output categories not to build
+    #[serde(rename = "excludeOutputs", default)]
+    pub exclude_outputs: Vec<OutputCategory>,
+
+    /// CI runner labels to use for building on a system, overriding `--runs-on`
+    #[serde(rename = "runsOn", default)]
+    pub runs_on: BTreeMap<System, String>,
+
+    /// Remote builders for the `systems` of this sub-flake that are not being
+    /// built for, eg: `ssh://mac-mini aarch64-darwin` (see [Builder])
+    #[serde(default)]
+    pub builders: Vec<String>,
+
+    /// Names of the sub-flakes (of the same configuration) to build before this
+    /// one; it is skipped if any of them fails
+    #[serde(rename = "dependsOn", default)]
+    pub depends_on: Vec<String>,
 }
 
 impl Default for SubFlakish {
@@ -115,6 +411,12 @@ impl Default for SubFlakish {
             dir: ".".to_string(),
             override_inputs: BTreeMap::default(),
             systems: None,
+            extra_args: vec![],
+            include_outputs: vec![],
+            exclude_outputs: vec![],
+            runs_on: BTreeMap::default(),
+            builders: vec![],
+            depends_on: vec![],
         }
     }
 }
@@ -127,12 +429,29 @@ impl SubFlakish {
         }
     }
 
+    /// The remote builders of this sub-flake
+    pub fn builders(&self) -> Result<Vec<Builder>> {
+        self.builders
+            .iter()
+            .map(|b| Builder::from_str(b).map_err(anyhow::Error::msg))
+            .collect()
+    }
+
+    /// The outputs to build, as selected by both this sub-flake and [BuildConfig]
+    pub fn output_filter(&self, build_cfg: &BuildConfig) -> OutputFilter {
+        OutputFilter::new(&self.include_outputs, &self.exclude_outputs)
+            .and(&build_cfg.output_filter())
+    }
+
     /// Return the devour-flake `nix build` arguments for building all the outputs in this
     /// subflake configuration.
+    ///
+    /// `systems` is the list of systems to build for, usually [BuildConfig::systems].
     pub fn nix_build_args_for_flake(
         &self,
         build_cfg: &BuildConfig,
         flake_url: &FlakeUrl,
+        systems: &SystemsListFlakeRef,
     ) -> Vec<String> {
         std::iter::once(flake_url.sub_flake_url(self.dir.clone()).0)
             .chain(self.override_inputs.iter().flat_map(|(k, v)| {
@@ -148,19 +467,178 @@ impl SubFlakish {
             .chain([
                 "--override-input".to_string(),
                 "systems".to_string(),
-                build_cfg.systems.0 .0.clone(),
+                systems.0 .0.clone(),
             ])
             .chain(build_cfg.extra_nix_build_args.iter().cloned())
+            .chain(self.extra_nix_build_args())
             .collect()
     }
+
+    /// The sub-flake's `extraArgs`, adjusted to devour_flake's expectations
+    fn extra_nix_build_args(&self) -> Vec<String> {
+        let mut args = self.extra_args.clone();
+        devour_flake::transform_override_inputs(&mut args);
+        args
+    }
 }
 
 #[cfg(test)]
-#[cfg(feature = "integration_test")]
 mod tests {
     use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn test_nix_build_args_for_flake() {
+        let build_cfg = BuildConfig::parse_from(["build", "--", "--refresh"]);
+        let subflake = SubFlakish {
+            dir: "test".to_string(),
+            extra_args: vec![
+                "--impure".to_string(),
+                "--override-input".to_string(),
+                "foo".to_string(),
+                "path:./foo".to_string(),
+            ],
+            ..SubFlakish::default()
+        };
+        assert_eq!(
+            subflake.nix_build_args_for_flake(
+                &build_cfg,
+                &FlakeUrl("github:srid/nixci".into()),
+                &build_cfg.systems
+            ),
+            vec![
+                "github:srid/nixci?dir=test",
+                "--override-input",
+                "systems",
+                "github:nix-systems/empty",
+                "--refresh",
+                "--impure",
+                "--override-input",
+                "flake/foo",
+                "path:./foo",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("dev", "dev"));
+        assert!(!glob_match("dev", "devx"));
+        assert!(glob_match("example-*", "example-foo"));
+        assert!(glob_match("example-*", "example-"));
+        assert!(!glob_match("example-*", "examples"));
+        assert!(glob_match("*-test", "foo-bar-test"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(glob_match("te?t", "test"));
+        assert!(glob_match("*", ""));
+    }
+
+    #[test]
+    fn test_subflake_selection() {
+        let sel = SubflakeSelection::parse("{dev,test}").unwrap();
+        assert_eq!(sel.include, vec!["dev", "test"]);
+        assert!(sel.matches("dev") && sel.matches("test") && !sel.matches("doc"));
+
+        let sel = SubflakeSelection::parse("example-{a,b*}").unwrap();
+        assert_eq!(sel.include, vec!["example-a", "example-b*"]);
+        assert!(SubflakeSelection::parse("{dev,test").is_err());
+        assert!(SubflakeSelection::parse("{a}{b}").is_err());
+
+        let mut sel = SubflakeSelection::parse("example-*").unwrap();
+        sel.exclude.push("example-slow".to_string());
+        assert!(sel.matches("example-fast"));
+        assert!(!sel.matches("example-slow"));
+        assert!(!sel.matches("dev"));
+        // Everything is selected by default
+        assert!(SubflakeSelection::default().matches("dev"));
+    }
+
+    #[test]
+    fn test_subflake_selection_check() {
+        let names = ["dev", "example-a", "example-b"];
+        let subflakes =
+            Config::for_test("default", names.map(|n| (n, SubFlakish::default())).into()).subflakes;
+        let check = |include: &[&str], exclude: &[&str]| {
+            SubflakeSelection {
+                include: include.iter().map(|s| s.to_string()).collect(),
+                exclude: exclude.iter().map(|s| s.to_string()).collect(),
+            }
+            .check(&subflakes, "default")
+            .map_err(|e| e.to_string())
+        };
+        assert!(check(&["dev", "example-*"], &["example-b"]).is_ok());
+        assert_eq!(
+            check(&["test"], &[]),
+            Err("No sub-flake matching 'test' in nixci configuration 'default' (available: dev, example-a, example-b)".to_string())
+        );
+        assert!(check(&[], &["doc"]).is_err());
+        assert_eq!(
+            check(&["example-*"], &["example-*"]),
+            Err("No sub-flake selected in nixci configuration 'default' (available: dev, example-a, example-b)".to_string())
+        );
+    }
+
+    #[test]
+    fn test_exclude_subflakes_across_configs() {
+        let mut cfgs = vec![
+            Config::for_test("default", vec![("dev", SubFlakish::default())]),
+            Config::for_test("extra", vec![("test", SubFlakish::default())]),
+        ];
+        assert_eq!(
+            exclude_subflakes(&mut cfgs, &["doc".to_string()])
+                .unwrap_err()
+                .to_string(),
+            "No sub-flake matching 'doc' in any nixci configuration (available: default.dev, extra.test)"
+        );
+        exclude_subflakes(&mut cfgs, &["test".to_string()]).unwrap();
+        assert!(cfgs[0].is_selected("dev"));
+        assert!(!cfgs[1].is_selected("test"));
+    }
+
+    #[test]
+    fn test_build_order() {
+        let with_deps = |deps: &[(&str, &[&str])]| {
+            Subflakes(
+                deps.iter()
+                    .map(|(name, deps)| {
+                        let subflake = SubFlakish {
+                            depends_on: deps.iter().map(|d| d.to_string()).collect(),
+                            ..SubFlakish::default()
+                        };
+                        (name.to_string(), subflake)
+                    })
+                    .collect(),
+            )
+        };
+        let subflakes = with_deps(&[
+            ("examples", &["lib"]),
+            ("integration", &["unit", "examples"]),
+            ("lib", &[]),
+            ("unit", &["lib"]),
+            ("docs", &[]),
+        ]);
+        assert_eq!(
+            subflakes.build_order("default").unwrap(),
+            vec!["docs", "lib", "examples", "unit", "integration"]
+        );
+
+        let subflakes = with_deps(&[("a", &["b"]), ("b", &["c"]), ("c", &["b"]), ("d", &[])]);
+        assert_eq!(
+            subflakes.build_order("default").unwrap_err().to_string(),
+            "Dependency cycle among the sub-flakes of nixci configuration 'default': b → c → b"
+        );
+        let subflakes = with_deps(&[("a", &["a"])]);
+        assert!(subflakes.build_order("default").is_err());
+
+        let subflakes = with_deps(&[("a", &["x"]), ("b", &[])]);
+        assert_eq!(
+            subflakes.build_order("default").unwrap_err().to_string(),
+            "Sub-flake 'a' depends on unknown sub-flake 'x' in nixci configuration 'default' (available: a, b)"
+        );
+    }
 
     #[tokio::test]
+    #[cfg(feature = "integration_test")]
     async fn test_config_loading() {
         // Testing this flake:
         // https://github.com/srid/haskell-flake/blob/76214cf8b0d77ed763d1f093ddce16febaf07365/flake.nix#L15-L67
@@ -172,7 +650,20 @@ mod tests {
             .await
             .unwrap();
         assert_eq!(cfg.name, "default");
-        assert_eq!(cfg.selected_subflake, Some("dev".to_string()));
+        assert_eq!(cfg.selection.include, vec!["dev".to_string()]);
         assert_eq!(cfg.subflakes.0.len(), 7);
     }
+
+    #[tokio::test]
+    #[cfg(feature = "integration_test")]
+    async fn test_all_configs_loading() {
+        let url = &FlakeUrl(
+            "github:srid/haskell-flake/76214cf8b0d77ed763d1f093ddce16febaf07365".to_string(),
+        );
+        let cfgs = Config::all_from_flake_url(&NixCmd::default(), url)
+            .await
+            .unwrap();
+        let default = cfgs.iter().find(|cfg| cfg.name == "default").unwrap();
+        assert_eq!(default.subflakes.0.len(), 7);
+    }
 }
@@ -1,2 +1 @@
-pub mod matrix;
 pub mod pull_request;
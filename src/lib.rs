@@ -1,23 +1,43 @@
+pub mod cache;
+pub mod ci;
 pub mod cli;
+pub mod closure_report;
+pub mod complete;
 pub mod config;
 pub mod github;
+pub mod list;
 pub mod logging;
 pub mod nix;
+pub mod report;
 
 use anyhow::{Context, Ok};
-use clap::CommandFactory;
 use clap_complete::generate;
-use std::collections::HashSet;
+use futures::{stream, StreamExt};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io;
+use std::path::PathBuf;
+use std::time::Instant;
 
-use cli::{BuildConfig, CliArgs};
+use ci::{MatrixFormat, RunnerLabels};
+use cli::{BuildConfig, CliArgs, MatrixConfig};
+use closure_report::ClosureReport;
 use colored::Colorize;
 use nix::{
+    builders::{builders_arg, read_builders_file, Builder},
     devour_flake::DevourFlakeOutput,
-    nix_store::{DrvOut, NixStoreCmd, StorePath},
+    nix_store::{Dependencies, DrvOut, NixStoreCmd, StoreBackend, StorePath},
+    path_info::NixPathInfoCmd,
+    system_list::SystemsListFlakeRef,
 };
 use nix_health::{traits::Checkable, NixHealth};
-use nix_rs::{command::NixCmd, config::NixConfig, flake::url::FlakeUrl, info::NixInfo};
+use nix_rs::{
+    command::NixCmd,
+    config::NixConfig,
+    flake::{system::System, url::FlakeUrl},
+    info::NixInfo,
+};
+use report::{BuildReport, SkipReason, SubflakeReport, SubflakeResult};
+use tokio::sync::watch;
 use tracing::instrument;
 
 /// Run nixci on the given [CliArgs], returning the built outputs in sorted order.
@@ -27,55 +47,124 @@ pub async fn nixci(args: CliArgs) -> anyhow::Result<Vec<StorePath>> {
 
     match args.command {
         cli::Command::Build(build_cfg) => {
-            let cfg = cli::Command::get_config(&args.nixcmd, &build_cfg.flake_ref).await?;
+            let cfgs = build_cfg.get_configs(&args.nixcmd).await?;
             let nix_info = NixInfo::from_nix(&args.nixcmd)
                 .await
                 .with_context(|| "Unable to gather nix info")?;
             // First, run the necessary health checks
-            check_nix_version(&cfg.flake_url, &nix_info).await?;
+            // All configurations are of the same flake
+            check_nix_version(&cfgs[0].flake_url, &nix_info).await?;
             // Then, do the build
             nixci_build(
                 &args.nixcmd,
+                args.store.as_deref(),
                 args.verbose,
                 &build_cfg,
-                &cfg,
+                &cfgs,
                 &nix_info.nix_config,
             )
             .await
         }
-        cli::Command::DumpGithubActionsMatrix {
-            systems, flake_ref, ..
-        } => {
-            let cfg = cli::Command::get_config(&args.nixcmd, &flake_ref).await?;
-            let matrix = github::matrix::GitHubMatrix::from(systems, &cfg.subflakes);
-            println!("{}", serde_json::to_string(&matrix)?);
+        cli::Command::DumpGithubActionsMatrix(matrix_cfg) => {
+            nixci_matrix(&args.nixcmd, &matrix_cfg, MatrixFormat::Github).await?;
+            Ok(vec![])
+        }
+        cli::Command::GitLabPipeline(matrix_cfg) => {
+            nixci_matrix(&args.nixcmd, &matrix_cfg, MatrixFormat::Gitlab).await?;
+            Ok(vec![])
+        }
+        cli::Command::Matrix { matrix_cfg, format } => {
+            nixci_matrix(&args.nixcmd, &matrix_cfg, format).await?;
+            Ok(vec![])
+        }
+        cli::Command::List { flake_ref, json } => {
+            nixci_list(&args.nixcmd, &flake_ref, json).await?;
             Ok(vec![])
         }
         cli::Command::Completion { shell } => {
-            let mut cli = CliArgs::command();
+            let mut cli = complete::visible_command();
             let name = cli.get_name().to_string();
-            generate(shell, &mut cli, name, &mut io::stdout());
+            generate(shell, &mut cli, name.clone(), &mut io::stdout());
+            if let Some(hook) = complete::dynamic_hook(shell, &name) {
+                print!("{}", hook);
+            }
+            Ok(vec![])
+        }
+        cli::Command::Complete { words } => {
+            for candidate in complete::complete(&args.nixcmd, &words).await? {
+                println!("{}", candidate);
+            }
             Ok(vec![])
         }
     }
 }
 
+async fn nixci_matrix(
+    cmd: &NixCmd,
+    matrix_cfg: &MatrixConfig,
+    format: MatrixFormat,
+) -> anyhow::Result<()> {
+    let mut cfg = cli::Command::get_config(cmd, &matrix_cfg.flake_ref).await?;
+    cfg.exclude_subflakes(&matrix_cfg.exclude_subflakes)?;
+    let runners = RunnerLabels::from(matrix_cfg.runs_on.clone());
+    let systems = matrix_cfg.get_systems(cmd, &cfg).await?;
+    let matrix = format.emit(&systems, &cfg, &runners)?;
+    println!("{}", matrix.trim_end());
+    Ok(())
+}
+
+async fn nixci_list(cmd: &NixCmd, flake_ref: &cli::FlakeRef, json: bool) -> anyhow::Result<()> {
+    let url = flake_ref.to_flake_url().await?;
+    let configs = if url.split_attr().1.is_none() {
+        config::Config::all_from_flake_url(cmd, &url).await?
+    } else {
+        vec![config::Config::from_flake_url(cmd, &url).await?]
+    };
+    if json {
+        println!("{}", serde_json::to_string(&list::to_json(&configs))?);
+    } else {
+        print!("{}", list::render_table(&configs));
+    }
+    Ok(())
+}
+
 async fn nixci_build(
     cmd: &NixCmd,
+    store: Option<&str>,
     verbose: bool,
     build_cfg: &BuildConfig,
-    cfg: &config::Config,
+    cfgs: &[config::Config],
     nix_config: &NixConfig,
 ) -> anyhow::Result<Vec<StorePath>> {
     let mut all_outs = HashSet::new();
+    // Read the baseline upfront, so as to fail before building if it is invalid.
+    let closure_baseline = build_cfg
+        .closure_baseline
+        .as_deref()
+        .map(ClosureReport::read_from)
+        .transpose()?;
+    let mut closure_regressions = vec![];
 
-    let all_devour_flake_outs = nixci_subflakes(cmd, verbose, build_cfg, cfg, nix_config).await?;
+    let mut report = nixci_subflakes(cmd, store, verbose, build_cfg, cfgs, nix_config).await?;
+    let stopped = !build_cfg.keep_going && report.failures().next().is_some();
 
-    if build_cfg.print_all_dependencies {
-        let all_deps = NixStoreCmd
-            .fetch_all_deps(all_devour_flake_outs.into_iter().collect())
-            .await?;
-        all_outs.extend(all_deps.into_iter());
+    let all_devour_flake_outs: HashSet<DrvOut> = report
+        .outputs()
+        .flat_map(|outs| outs.out_paths.iter().cloned())
+        .collect();
+
+    if stopped {
+        // Nothing to print; we bail below.
+    } else if build_cfg.print_all_dependencies {
+        let all_deps = nixci_deps(
+            cmd,
+            store,
+            build_cfg,
+            all_devour_flake_outs.into_iter().collect(),
+        )
+        .await?;
+        all_outs.extend(all_deps.paths);
+        report.without_deriver = all_deps.without_deriver;
     } else {
         let store_paths: HashSet<StorePath> = all_devour_flake_outs
             .into_iter()
@@ -84,73 +173,637 @@ async fn nixci_build(
         all_outs.extend(store_paths);
     }
 
-    for out in &all_outs {
-        println!("{}", out);
+    if !stopped && build_cfg.closure_report_enabled() {
+        let closures = nixci_closure_report(cmd, store, build_cfg, &report).await?;
+        closures.print(closure_baseline.as_ref());
+        if let Some(path) = &build_cfg.write_closure_baseline {
+            closures.write_to(path)?;
+        }
+        if let Some(baseline) = &closure_baseline {
+            closure_regressions = closures.regressions(baseline, build_cfg.closure_threshold);
+        }
+        report.closure_sizes = closures.sizes;
+    }
+
+    if let Some(path) = &build_cfg.report {
+        report.write_to(path)?;
+    }
+    if build_cfg.json {
+        println!("{}", serde_json::to_string(&report)?);
+    }
+    if build_cfg.keep_going {
+        report.print_summary();
+    } else if let Some(failure) = report.failures().next() {
+        if let SubflakeResult::Failure { error } = &failure.result {
+            anyhow::bail!("{}", error);
+        }
+    } else {
+        report.print_skipped();
+    }
+
+    if !build_cfg.json {
+        for out in &all_outs {
+            println!("{}", out);
+        }
+    }
+
+    let failures: Vec<String> = report.failures().map(SubflakeReport::full_name).collect();
+    if !failures.is_empty() {
+        anyhow::bail!(
+            "{} sub-flake(s) failed: {}",
+            failures.len(),
+            failures.join(", ")
+        );
+    }
+
+    for r in &closure_regressions {
+        tracing::error!(
+            "📈 {} {}",
+            r.key,
+            format!(
+                "closure grew by {:.1}% ({} → {})",
+                r.growth(),
+                closure_report::format_size(r.baseline),
+                closure_report::format_size(r.current)
+            )
+            .red()
+        );
+    }
+    if !closure_regressions.is_empty() {
+        anyhow::bail!(
+            "{} closure(s) grew by more than {}% over the baseline",
+            closure_regressions.len(),
+            build_cfg.closure_threshold
+        );
     }
 
     Ok(all_outs.into_iter().collect())
 }
 
+/// Compute the closure size of each output built in the given [BuildReport]
+///
+/// Outputs are grouped by the flake attribute producing them, if known.
+async fn nixci_closure_report(
+    cmd: &NixCmd,
+    store: Option<&str>,
+    build_cfg: &BuildConfig,
+    report: &BuildReport,
+) -> anyhow::Result<ClosureReport> {
+    let mut entries: Vec<(String, Vec<PathBuf>)> = vec![];
+    for r in &report.subflakes {
+        let SubflakeResult::Success { outputs } = &r.result else {
+            continue;
+        };
+        if outputs.by_attr.is_empty() {
+            for out in &outputs.out_paths {
+                let name = out.0.file_name().unwrap_or_default().to_string_lossy();
+                // Drop the hash, which changes across builds
+                let name = name.split_once('-').map_or(&*name, |(_, n)| n);
+                entries.push((format!("{}#{}", r.full_name(), name), vec![out.0.clone()]));
+            }
+        } else {
+            for (attr, outs) in &outputs.by_attr {
+                let paths = outs.iter().map(|out| out.0.clone()).collect();
+                entries.push((format!("{}#{}", r.full_name(), attr), paths));
+            }
+        }
+    }
+
+    let mut sizes = stream::iter(entries)
+        .map(|(key, paths)| async move {
+            let size = match build_cfg.store_backend {
+                StoreBackend::Legacy => nix_store_cmd(store).closure_size(&paths).await?,
+                StoreBackend::Modern => {
+                    NixPathInfoCmd::new(cmd.clone(), store.map(String::from))
+                        .closure_size(&paths)
+                        .await?
+                }
+            };
+            anyhow::Ok((key, size))
+        })
+        .buffer_unordered(4);
+    let mut closures = ClosureReport::default();
+    while let Some(result) = sizes.next().await {
+        let (key, size) = result?;
+        closures.insert(key, size)?;
+    }
+    Ok(closures)
+}
+
+/// Build the sub-flakes of the given [config::Config]s, returning a [BuildReport]
+///
+/// Sub-flakes are built after those they depend on, and skipped if any of
+/// those fails. Unless [BuildConfig::keep_going] is set, building stops at the
+/// first sub-flake that fails, which will be the last entry in the report.
 async fn nixci_subflakes(
     cmd: &NixCmd,
+    store: Option<&str>,
     verbose: bool,
     build_cfg: &BuildConfig,
-    cfg: &config::Config,
+    cfgs: &[config::Config],
     nix_config: &NixConfig,
-) -> anyhow::Result<HashSet<DrvOut>> {
-    let mut result = HashSet::new();
-    let systems = build_cfg.get_systems(cmd, nix_config).await?;
-
-    for (subflake_name, subflake) in &cfg.subflakes.0 {
-        let name = format!("{}.{}", cfg.name, subflake_name).italic();
-        if cfg
-            .selected_subflake
-            .as_ref()
-            .is_some_and(|s| s != subflake_name)
-        {
-            tracing::info!("🍊 {} {}", name, "skipped (deselected out)".dimmed());
-            continue;
+) -> anyhow::Result<BuildReport> {
+    let jobs = subflake_jobs(cfgs)?;
+    let ctx = BuildContext {
+        cmd,
+        store,
+        verbose,
+        build_cfg,
+        outcomes: Outcomes::new(&jobs),
+        systems: build_cfg.get_systems(cmd, nix_config).await?,
+        substituters: build_cfg.get_substituters(nix_config),
+        builders: match &build_cfg.builders_file {
+            Some(path) => read_builders_file(path)?,
+            None => vec![],
+        },
+    };
+    let mut report = BuildReport {
+        systems: ctx.systems.clone(),
+        subflakes: vec![],
+        without_deriver: vec![],
+        closure_sizes: BTreeMap::new(),
+    };
+
+    // Jobs come after their dependencies, which are thus always in flight (or
+    // done) when a job waits for them.
+    let mut reports = stream::iter(jobs)
+        .map(|job| nixci_subflake_report(&ctx, job))
+        .buffered(build_cfg.jobs.get());
+
+    while let Some(subflake_report) = reports.next().await {
+        let failure = match &subflake_report.result {
+            SubflakeResult::Failure { error } => Some(error.clone()),
+            _ => None,
+        };
+        let name = subflake_report.full_name().italic();
+        report.subflakes.push(subflake_report);
+        if let Some(error) = failure {
+            if !build_cfg.keep_going {
+                // Dropping the stream kills any in-flight builds.
+                break;
+            }
+            tracing::error!("🍅 {} {}: {}", name, "failed".red(), error);
         }
-        tracing::info!("🍎 {}", name);
-        if subflake.can_build_on(&systems) {
-            let outs = nixci_subflake(
-                cmd,
-                verbose,
-                build_cfg,
-                &cfg.flake_url,
-                subflake_name,
+    }
+
+    Ok(report)
+}
+
+/// A sub-flake of a [config::Config] to process
+struct SubflakeJob<'a> {
+    cfg: &'a config::Config,
+    name: &'a str,
+    subflake: &'a config::SubFlakish,
+    /// The full names of the sub-flakes it depends on
+    depends_on: Vec<String>,
+    /// The full name of the selected sub-flake with an identical definition
+    /// that comes first, if any; it is built instead of this one.
+    duplicate_of: Option<String>,
+}
+
+impl SubflakeJob<'_> {
+    fn full_name(&self) -> String {
+        format!("{}.{}", self.cfg.name, self.name)
+    }
+}
+
+/// The sub-flakes of all given configurations, in build order (see
+/// [config::Subflakes::build_order])
+fn subflake_jobs(cfgs: &[config::Config]) -> anyhow::Result<Vec<SubflakeJob<'_>>> {
+    let mut jobs: Vec<SubflakeJob> = vec![];
+    for cfg in cfgs {
+        for name in cfg.subflakes.build_order(&cfg.name)? {
+            let subflake = &cfg.subflakes.0[name];
+            let duplicate_of = if cfg.is_selected(name) {
+                jobs.iter()
+                    .find(|job| {
+                        job.duplicate_of.is_none()
+                            && job.cfg.is_selected(job.name)
+                            && job.subflake == subflake
+                    })
+                    .map(SubflakeJob::full_name)
+            } else {
+                None
+            };
+            let depends_on = subflake
+                .depends_on
+                .iter()
+                .map(|dep| format!("{}.{}", cfg.name, dep))
+                .collect();
+            jobs.push(SubflakeJob {
+                cfg,
+                name,
                 subflake,
-            )
-            .await?;
-            result.extend(outs.0);
+                depends_on,
+                duplicate_of,
+            });
+        }
+    }
+    Ok(jobs)
+}
+
+/// Whether each sub-flake passed, for the sub-flakes depending on it to wait on
+///
+/// A sub-flake passes unless it fails, or is skipped because a dependency (or
+/// the sub-flake it duplicates) did not pass.
+struct Outcomes(HashMap<String, watch::Sender<Option<bool>>>);
+
+impl Outcomes {
+    fn new(jobs: &[SubflakeJob<'_>]) -> Self {
+        Outcomes(
+            jobs.iter()
+                .map(|job| (job.full_name(), watch::channel(None).0))
+                .collect(),
+        )
+    }
+
+    /// Record whether the given sub-flake passed
+    fn set(&self, full_name: &str, passed: bool) {
+        if let Some(outcome) = self.0.get(full_name) {
+            outcome.send_replace(Some(passed));
+        }
+    }
+
+    /// Wait for the given sub-flakes to be processed, returning the first of
+    /// them that did not pass, if any
+    async fn first_failed(&self, full_names: &[String]) -> Option<String> {
+        for full_name in full_names {
+            let Some(outcome) = self.0.get(full_name) else {
+                continue;
+            };
+            let mut outcome = outcome.subscribe();
+            let passed = outcome
+                .wait_for(Option::is_some)
+                .await
+                .is_ok_and(|passed| *passed == Some(true));
+            if !passed {
+                return Some(full_name.clone());
+            }
+        }
+        None
+    }
+}
+
+/// What the builds of all sub-flakes share
+struct BuildContext<'a> {
+    cmd: &'a NixCmd,
+    /// The store to build in, instead of the default one
+    store: Option<&'a str>,
+    verbose: bool,
+    build_cfg: &'a BuildConfig,
+    /// Whether each sub-flake passed, once processed
+    outcomes: Outcomes,
+    /// The systems to build for
+    systems: Vec<System>,
+    /// The substituters to look up outputs in, with [BuildConfig::skip_cached]
+    substituters: Vec<String>,
+    /// The remote builders from [BuildConfig::builders_file]
+    builders: Vec<Builder>,
+}
+
+/// How to build a sub-flake none of whose systems are being built for
+#[derive(Debug)]
+struct RemoteBuild {
+    /// The sub-flake's systems that the builders can build for
+    systems: Vec<System>,
+    /// The builders for those systems
+    builders: Vec<Builder>,
+}
+
+impl RemoteBuild {
+    /// Route the sub-flake to the builders (its own, and those in `ctx`) that
+    /// can build any of its `systems`, if there are any
+    fn route(
+        ctx: &BuildContext<'_>,
+        subflake: &config::SubFlakish,
+    ) -> anyhow::Result<Option<RemoteBuild>> {
+        let Some(wanted) = &subflake.systems else {
+            return Ok(None);
+        };
+        let builders: Vec<Builder> = subflake
+            .builders()?
+            .into_iter()
+            .chain(ctx.builders.iter().cloned())
+            .filter(|b| b.systems.iter().any(|s| wanted.contains(s)))
+            .collect();
+        let systems: Vec<System> = wanted
+            .iter()
+            .filter(|s| builders.iter().any(|b| b.systems.contains(s)))
+            .cloned()
+            .collect();
+        Ok((!systems.is_empty()).then_some(RemoteBuild { systems, builders }))
+    }
+}
+
+/// Build a single sub-flake (unless it is to be skipped), returning its [SubflakeReport]
+async fn nixci_subflake_report(ctx: &BuildContext<'_>, job: SubflakeJob<'_>) -> SubflakeReport {
+    let full_name = job.full_name();
+    let SubflakeJob {
+        cfg,
+        name: subflake_name,
+        subflake,
+        depends_on,
+        duplicate_of,
+    } = job;
+    let name = full_name.italic();
+    let start = Instant::now();
+    let categories = subflake.output_filter(ctx.build_cfg).categories();
+    let result = if !cfg.is_selected(subflake_name) {
+        skip_subflake(&name, SkipReason::Deselected)
+    } else if let Some(dependency) = ctx.outcomes.first_failed(&depends_on).await {
+        skip_subflake(&name, SkipReason::DependencyFailed { dependency })
+    } else if let Some(of) = duplicate_of {
+        skip_subflake(&name, SkipReason::Duplicate { of })
+    } else if categories.is_empty() {
+        skip_subflake(&name, SkipReason::NoOutputs)
+    } else {
+        tracing::info!("🍎 {}", name);
+        let native = subflake.can_build_on(&ctx.systems);
+        let remote = if native {
+            Result::Ok(None)
         } else {
-            tracing::info!(
-                "🍊 {} {}",
-                name,
-                "skipped (cannot build on this system)".dimmed()
-            );
+            RemoteBuild::route(ctx, subflake)
+        };
+        match remote {
+            Err(err) => SubflakeResult::Failure {
+                error: format!("{:#}", err),
+            },
+            Result::Ok(None) if !native => skip_subflake(
+                &name,
+                SkipReason::UnsupportedSystems {
+                    subflake_systems: subflake.systems.clone().unwrap_or_default(),
+                    build_systems: ctx.systems.clone(),
+                },
+            ),
+            Result::Ok(remote) => {
+                if let Some(remote) = &remote {
+                    let builders: Vec<&str> =
+                        remote.builders.iter().map(|b| b.uri.as_str()).collect();
+                    tracing::info!(
+                        "🚚 {} {}",
+                        name,
+                        format!("building on {}", builders.join(", ")).dimmed()
+                    );
+                }
+                match nixci_subflake(ctx, &cfg.flake_url, &name, subflake, remote.as_ref()).await {
+                    Result::Ok(Some(outputs)) => SubflakeResult::Success { outputs },
+                    Result::Ok(None) => skip_subflake(&name, SkipReason::AlreadyCached),
+                    Err(err) => SubflakeResult::Failure {
+                        error: format!("{:#}", err),
+                    },
+                }
+            }
         }
+    };
+    let passed = match &result {
+        SubflakeResult::Failure { .. }
+        | SubflakeResult::Skipped {
+            reason: SkipReason::DependencyFailed { .. },
+        } => false,
+        SubflakeResult::Skipped {
+            reason: SkipReason::Duplicate { of },
+        } => ctx
+            .outcomes
+            .first_failed(std::slice::from_ref(of))
+            .await
+            .is_none(),
+        _ => true,
+    };
+    ctx.outcomes.set(&full_name, passed);
+    SubflakeReport {
+        config: cfg.name.clone(),
+        name: subflake_name.to_string(),
+        flake_url: cfg.flake_url.sub_flake_url(subflake.dir.clone()),
+        subflake: subflake.clone(),
+        categories,
+        duration: start.elapsed(),
+        result,
     }
+}
 
-    Ok(result)
+/// Log that the sub-flake is skipped, returning the corresponding [SubflakeResult]
+fn skip_subflake(name: impl std::fmt::Display, reason: SkipReason) -> SubflakeResult {
+    tracing::info!("🍊 {} {}", name, format!("skipped ({})", reason).dimmed());
+    SubflakeResult::Skipped { reason }
 }
 
-#[instrument(skip(build_cfg, url))]
+/// Build a single sub-flake, returning its outputs
+///
+/// If `remote` is given, the sub-flake is built for its systems on its builders,
+/// instead of for [BuildContext::systems].
+///
+/// Returns `None` if, with [BuildConfig::skip_cached], all its outputs are
+/// already available in a substituter.
+#[instrument(skip(ctx))]
 async fn nixci_subflake(
-    cmd: &NixCmd,
-    verbose: bool,
-    build_cfg: &BuildConfig,
+    ctx: &BuildContext<'_>,
     url: &FlakeUrl,
-    subflake_name: &str,
+    name: &str,
     subflake: &config::SubFlakish,
-) -> anyhow::Result<DevourFlakeOutput> {
+    remote: Option<&RemoteBuild>,
+) -> anyhow::Result<Option<DevourFlakeOutput>> {
+    let (cmd, build_cfg) = (ctx.cmd, ctx.build_cfg);
+    // Prefix logs with the sub-flake name when they may be interleaved.
+    let log_prefix = (build_cfg.jobs.get() > 1).then(|| format!("[{}]", name));
     if subflake.override_inputs.is_empty() {
-        nix::lock::nix_flake_lock_check(cmd, &url.sub_flake_url(subflake.dir.clone())).await?;
+        nix::lock::nix_flake_lock_check(
+            cmd,
+            ctx.store,
+            ctx.verbose,
+            log_prefix.clone(),
+            &url.sub_flake_url(subflake.dir.clone()),
+        )
+        .await?;
+    }
+
+    let (systems, nix_args) = match remote {
+        None => (
+            ctx.systems.clone(),
+            subflake.nix_build_args_for_flake(build_cfg, url, &build_cfg.systems),
+        ),
+        Some(remote) => {
+            let systems_ref = SystemsListFlakeRef::from_systems(&remote.systems)?;
+            let mut nix_args = subflake.nix_build_args_for_flake(build_cfg, url, &systems_ref);
+            let builders: Vec<&Builder> = remote.builders.iter().collect();
+            nix_args.extend(["--builders".to_string(), builders_arg(&builders)]);
+            (remote.systems.clone(), nix_args)
+        }
+    };
+    let outputs = subflake.output_filter(build_cfg);
+
+    if build_cfg.skip_cached {
+        match nix::attribution::evaluate_out_paths(
+            cmd,
+            ctx.store,
+            &systems,
+            &outputs.categories(),
+            &nix_args,
+        )
+        .await
+        {
+            Result::Ok(by_attr) => {
+                let out_paths: HashSet<PathBuf> =
+                    by_attr.into_values().flatten().map(|out| out.0).collect();
+                let uncached = nixci_uncached(ctx, out_paths.clone()).await;
+                tracing::info!(
+                    "📦 {} {}",
+                    name,
+                    format!(
+                        "{} of {} outputs already cached",
+                        out_paths.len() - uncached.len(),
+                        out_paths.len()
+                    )
+                    .dimmed()
+                );
+                for path in &uncached {
+                    tracing::debug!("Not cached: {}", path.display());
+                }
+                if uncached.is_empty() {
+                    return Ok(None);
+                }
+            }
+            // The build reports evaluation errors itself, if they are real.
+            Err(err) => tracing::warn!(
+                "Unable to evaluate the outputs of {}, building it: {:#}",
+                name,
+                err
+            ),
+        }
+    }
+
+    let mut outs = nix::devour_flake::devour_flake(
+        cmd,
+        ctx.store,
+        ctx.verbose,
+        log_prefix,
+        &outputs,
+        nix_args.clone(),
+    )
+    .await?;
+
+    // Attribution costs an evaluation, so do it only if it will be reported.
+    if build_cfg.json || build_cfg.report.is_some() || build_cfg.closure_report_enabled() {
+        match nix::attribution::attribute_outputs(
+            cmd,
+            ctx.store,
+            &systems,
+            &outputs.categories(),
+            &nix_args,
+            &outs.out_paths,
+        )
+        .await
+        {
+            Result::Ok(by_attr) => outs.by_attr = by_attr,
+            Err(err) => tracing::warn!("Unable to attribute outputs of {}: {:#}", name, err),
+        }
+    }
+
+    if let Some(to) = &build_cfg.push_to {
+        nixci_push(cmd, ctx.store, build_cfg, to, name, &outs).await?;
+    }
+    Ok(Some(outs))
+}
+
+/// Return those of `paths` not available in any of the substituters
+///
+/// A substituter that cannot be queried is treated as not having any path.
+async fn nixci_uncached(ctx: &BuildContext<'_>, mut paths: HashSet<PathBuf>) -> HashSet<PathBuf> {
+    for substituter in &ctx.substituters {
+        if paths.is_empty() {
+            break;
+        }
+        let query: Vec<PathBuf> = paths.iter().cloned().collect();
+        match nix::substituter::query_cached_paths(ctx.cmd, substituter, &query).await {
+            Result::Ok(valid) => paths.retain(|p| !valid.contains(p)),
+            Err(err) => tracing::warn!("Unable to query substituter {}: {}", substituter, err),
+        }
     }
+    paths
+}
 
-    let nix_args = subflake.nix_build_args_for_flake(build_cfg, url);
-    let outs = nix::devour_flake::devour_flake(cmd, verbose, nix_args).await?;
-    Ok(outs)
+/// Copy the built outputs (and, if requested, all their dependencies) to the store at `to`
+async fn nixci_push(
+    cmd: &NixCmd,
+    store: Option<&str>,
+    build_cfg: &BuildConfig,
+    to: &str,
+    name: &str,
+    outs: &DevourFlakeOutput,
+) -> anyhow::Result<()> {
+    let paths: Vec<PathBuf> = if build_cfg.print_all_dependencies {
+        nixci_deps(
+            cmd,
+            store,
+            build_cfg,
+            outs.out_paths.iter().cloned().collect(),
+        )
+        .await?
+        .paths
+        .into_iter()
+        .map(|p| p.as_path().clone())
+        .collect()
+    } else {
+        outs.out_paths.iter().map(|out| out.0.clone()).collect()
+    };
+    tracing::info!(
+        "📤 {} {}",
+        name,
+        format!("pushing {} paths to {}", paths.len(), to).dimmed()
+    );
+    let failures = nix::copy::nix_copy(cmd, store, to, &paths)
+        .await
+        .with_context(|| format!("Failed to push {} to {}", name, to))?;
+    for failure in &failures {
+        tracing::error!("❌ {}: {}", failure.path.display(), failure.error);
+    }
+    if !failures.is_empty() {
+        anyhow::bail!(
+            "Failed to push {} of {} paths to {}",
+            failures.len(),
+            paths.len(),
+            to
+        );
+    }
+    Ok(())
+}
+
+/// Fetch the dependencies of the given outputs selected by [BuildConfig::closure]
+///
+/// Outputs without a valid deriver are logged, as only their runtime closure is included.
+async fn nixci_deps(
+    cmd: &NixCmd,
+    store: Option<&str>,
+    build_cfg: &BuildConfig,
+    out_paths: Vec<DrvOut>,
+) -> anyhow::Result<Dependencies> {
+    let deps = match build_cfg.store_backend {
+        StoreBackend::Legacy => {
+            nix_store_cmd(store)
+                .fetch_deps(build_cfg.closure, out_paths)
+                .await?
+        }
+        StoreBackend::Modern => {
+            NixPathInfoCmd::new(cmd.clone(), store.map(String::from))
+                .fetch_deps(build_cfg.closure, out_paths)
+                .await?
+        }
+    };
+    for path in &deps.without_deriver {
+        tracing::warn!(
+            "⚠️  {} {}",
+            path.display(),
+            "has no valid deriver; including only its runtime closure".dimmed()
+        );
+    }
+    Ok(deps)
+}
+
+/// The `nix-store` command querying `store` (or the default store)
+fn nix_store_cmd(store: Option<&str>) -> NixStoreCmd {
+    NixStoreCmd {
+        store: store.map(String::from),
+        ..NixStoreCmd::default()
+    }
 }
 
 pub async fn check_nix_version(flake_url: &FlakeUrl, nix_info: &NixInfo) -> anyhow::Result<()> {
@@ -163,3 +816,84 @@ pub async fn check_nix_version(flake_url: &FlakeUrl, nix_info: &NixInfo) -> anyh
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::{Config, SubFlakish};
+
+    /// A configuration whose sub-flakes are in the given (name, dir) pairs
+    fn config(name: &str, subflakes: &[(&str, &str)]) -> Config {
+        let subflakes = subflakes
+            .iter()
+            .map(|&(name, dir)| {
+                let subflake = SubFlakish {
+                    dir: dir.to_string(),
+                    ..SubFlakish::default()
+                };
+                (name, subflake)
+            })
+            .collect();
+        Config::for_test(name, subflakes)
+    }
+
+    #[test]
+    fn test_subflake_jobs_dedupe() {
+        let mut extra = config("extra", &[("dev", "./dev"), ("test", "./test")]);
+        let cfgs = vec![
+            config("default", &[("dev", "./dev"), ("doc", "./doc")]),
+            config("darwin", &[("dev", "./dev"), ("main", "./doc")]),
+        ];
+        let jobs: Vec<_> = subflake_jobs(&cfgs)
+            .unwrap()
+            .into_iter()
+            .map(|job| (format!("{}.{}", job.cfg.name, job.name), job.duplicate_of))
+            .collect();
+        assert_eq!(
+            jobs,
+            vec![
+                ("default.dev".to_string(), None),
+                ("default.doc".to_string(), None),
+                ("darwin.dev".to_string(), Some("default.dev".to_string())),
+                ("darwin.main".to_string(), Some("default.doc".to_string())),
+            ]
+        );
+
+        // A deselected sub-flake is not built, so an identical one elsewhere is
+        extra.selection.exclude.push("dev".to_string());
+        let cfgs = vec![extra, config("other", &[("dev", "./dev")])];
+        let duplicates: Vec<_> = subflake_jobs(&cfgs)
+            .unwrap()
+            .into_iter()
+            .map(|job| job.duplicate_of)
+            .collect();
+        assert_eq!(duplicates, vec![None, None, None]);
+    }
+
+    #[tokio::test]
+    async fn test_outcomes() {
+        let mut cfg = config("default", &[("lib", "./lib"), ("examples", "./examples")]);
+        let examples = cfg.subflakes.0.get_mut("examples").unwrap();
+        examples.depends_on = vec!["lib".to_string()];
+        let cfgs = vec![cfg];
+        let jobs = subflake_jobs(&cfgs).unwrap();
+        let order: Vec<_> = jobs.iter().map(SubflakeJob::full_name).collect();
+        assert_eq!(order, vec!["default.lib", "default.examples"]);
+        assert_eq!(jobs[1].depends_on, vec!["default.lib"]);
+
+        // The dependent waits until the dependency is processed
+        let outcomes = std::sync::Arc::new(Outcomes::new(&jobs));
+        let deps = jobs[1].depends_on.clone();
+        let waiting = tokio::spawn({
+            let (outcomes, deps) = (outcomes.clone(), deps.clone());
+            async move { outcomes.first_failed(&deps).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+        outcomes.set("default.lib", false);
+        assert_eq!(waiting.await.unwrap(), Some("default.lib".to_string()));
+
+        outcomes.set("default.lib", true);
+        assert_eq!(outcomes.first_failed(&deps).await, None);
+    }
+}
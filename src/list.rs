@@ -0,0 +1,126 @@
+//! Listing of the nixci configurations of a flake (`nixci list`)
+use std::collections::BTreeMap;
+
+use crate::config::{Config, Subflakes};
+
+/// Render the configurations as a table, with a row per sub-flake
+pub fn render_table(configs: &[Config]) -> String {
+    let header = ["CONFIG", "SUBFLAKE", "DIR", "OVERRIDE INPUTS", "SYSTEMS"].map(String::from);
+    let mut rows = vec![header];
+    for cfg in configs {
+        for (name, subflake) in &cfg.subflakes.0 {
+            let override_inputs = subflake
+                .override_inputs
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, v.0))
+                .collect::<Vec<_>>();
+            let systems = match &subflake.systems {
+                Some(systems) => systems.iter().map(|s| s.to_string()).collect(),
+                None => vec!["(all)".to_string()],
+            };
+            rows.push([
+                cfg.name.clone(),
+                name.clone(),
+                subflake.dir.clone(),
+                or_dash(override_inputs.join(" ")),
+                or_dash(systems.join(",")),
+            ]);
+        }
+    }
+    let mut widths = [0; 5];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    rows.iter()
+        .map(|row| {
+            let line = row
+                .iter()
+                .zip(widths)
+                .map(|(cell, width)| format!("{:width$}", cell))
+                .collect::<Vec<_>>()
+                .join("  ");
+            line.trim_end().to_string() + "\n"
+        })
+        .collect()
+}
+
+fn or_dash(s: String) -> String {
+    if s.is_empty() {
+        "-".to_string()
+    } else {
+        s
+    }
+}
+
+/// The configurations as JSON, keyed by configuration and then sub-flake name
+pub fn to_json(configs: &[Config]) -> BTreeMap<&str, &Subflakes> {
+    configs
+        .iter()
+        .map(|cfg| (cfg.name.as_str(), &cfg.subflakes))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SubFlakish;
+    use nix_rs::flake::url::FlakeUrl;
+
+    fn configs() -> Vec<Config> {
+        vec![
+            Config::for_test(
+                "default",
+                vec![
+                    (
+                        "dev",
+                        SubFlakish {
+                            dir: "./dev".to_string(),
+                            override_inputs: BTreeMap::from([(
+                                "haskell-flake".to_string(),
+                                FlakeUrl(".".to_string()),
+                            )]),
+                            ..SubFlakish::default()
+                        },
+                    ),
+                    (
+                        "darwin",
+                        SubFlakish {
+                            systems: Some(vec!["aarch64-darwin".into(), "x86_64-darwin".into()]),
+                            ..SubFlakish::default()
+                        },
+                    ),
+                ],
+            ),
+            Config::for_test("docs", vec![("<root>", SubFlakish::default())]),
+        ]
+    }
+
+    #[test]
+    fn test_render_table() {
+        assert_eq!(
+            render_table(&configs()),
+            "\
+CONFIG   SUBFLAKE  DIR    OVERRIDE INPUTS  SYSTEMS
+default  darwin    .      -                aarch64-darwin,x86_64-darwin
+default  dev       ./dev  haskell-flake=.  (all)
+docs     <root>    .      -                (all)
+"
+        );
+    }
+
+    #[test]
+    fn test_to_json() {
+        let json = serde_json::to_value(to_json(&configs())).unwrap();
+        assert_eq!(
+            json["default"]["dev"]["overrideInputs"]["haskell-flake"],
+            "."
+        );
+        assert_eq!(
+            json["default"]["darwin"]["systems"],
+            serde_json::json!(["aarch64-darwin", "x86_64-darwin"])
+        );
+        assert_eq!(json["docs"]["<root>"]["dir"], ".");
+    }
+}
@@ -0,0 +1,228 @@
+//! Attribute the outputs built by devour-flake to the flake output attributes producing them
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::PathBuf,
+};
+
+use anyhow::Result;
+use nix_rs::{command::NixCmd, flake::system::System};
+
+use super::{
+    devour_flake::{write_generated_flake, OutputCategory},
+    nix_store::DrvOut,
+};
+
+impl OutputCategory {
+    /// Nix expression evaluating to a list of `{ name = <attr path>; value = <out paths>; }`
+    ///
+    /// Refers to the helpers defined in [attribution_flake].
+    fn attribution_expr(&self) -> &'static str {
+        match self {
+            OutputCategory::Packages => r#"perSystem "packages" outPaths"#,
+            OutputCategory::Apps => r#"perSystem "apps" (app: [ app.program ])"#,
+            OutputCategory::Checks => r#"perSystem "checks" outPaths"#,
+            OutputCategory::DevShells => r#"perSystem "devShells" outPaths"#,
+            OutputCategory::NixosConfigurations => r#"toplevel "nixosConfigurations""#,
+            OutputCategory::DarwinConfigurations => r#"toplevel "darwinConfigurations""#,
+            OutputCategory::HomeConfigurations => "home",
+        }
+    }
+}
+
+/// The `flake.nix` of a flake whose `nixciOutPaths` output maps each output
+/// attribute of its `flake` input to its output paths.
+fn attribution_flake(systems: &[System], categories: &[OutputCategory]) -> String {
+    let systems = systems
+        .iter()
+        .map(|s| format!("\"{}\"", s))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let exprs = if categories.is_empty() {
+        "[ ]".to_string()
+    } else {
+        categories
+            .iter()
+            .map(|c| format!("({})", c.attribution_expr()))
+            .collect::<Vec<_>>()
+            .join(" ++ ")
+    };
+    format!(
+        r#"# Generated by nixci, to find the output paths of each flake output attribute
+{{
+  inputs.flake = {{ }};
+  outputs = {{ flake, ... }}:
+    let
+      systems = [ {systems} ];
+      outPaths = drv: if drv ? outputs then map (o: drv.${{o}}.outPath) drv.outputs else [ drv.outPath ];
+      attrsOf = category: attrs: f: map
+        (name: {{ name = "${{category}}.${{name}}"; value = f attrs.${{name}}; }})
+        (builtins.attrNames attrs);
+      perSystem = category: f: builtins.concatMap
+        (system: attrsOf "${{category}}.${{system}}" (flake.${{category}}.${{system}} or {{ }}) f)
+        systems;
+      toplevel = category: attrsOf category (flake.${{category}} or {{ }})
+        (cfg: outPaths cfg.config.system.build.toplevel);
+      home = builtins.concatMap
+        (system: attrsOf "legacyPackages.${{system}}.homeConfigurations"
+          (flake.legacyPackages.${{system}}.homeConfigurations or {{ }})
+          (cfg: outPaths cfg.activationPackage))
+        systems;
+    in
+    {{
+      nixciOutPaths = builtins.listToAttrs ({exprs});
+    }};
+}}
+"#
+    )
+}
+
+/// Options of `nix build` which `nix eval` does not accept, along with whether
+/// they take a value
+const BUILD_ONLY_OPTIONS: &[(&str, bool)] = &[
+    ("-o", true),
+    ("--out-link", true),
+    ("--profile", true),
+    ("--no-link", false),
+    ("--print-out-paths", false),
+    ("--dry-run", false),
+    ("--rebuild", false),
+];
+
+/// Return the given [super::devour_flake::devour_flake] arguments, adjusted
+/// to evaluate the attribution flake instead.
+///
+/// `args` start with the user's flake URL. `--override-input` arguments are
+/// made to refer to the inputs of the attribution flake, and `nix build`-only
+/// options are dropped; all other arguments (like `--impure` or `--option`)
+/// are kept, so that the flake evaluates as it does when built.
+fn attribution_args(args: &[String]) -> Vec<String> {
+    let mut result = vec![];
+    if let Some(url) = args.first() {
+        result.extend([
+            "--override-input".to_string(),
+            "flake".to_string(),
+            url.clone(),
+        ]);
+    }
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        if arg == "--override-input" {
+            if let (Some(input), Some(url)) = (iter.next(), iter.next()) {
+                // Skip devour-flake's own inputs, like `systems`
+                if input.starts_with("flake/") {
+                    result.extend([arg.clone(), input.clone(), url.clone()]);
+                }
+            }
+        } else if let Some((_, takes_value)) = BUILD_ONLY_OPTIONS.iter().find(|(opt, _)| opt == arg)
+        {
+            if *takes_value {
+                iter.next();
+            }
+        } else {
+            result.push(arg.clone());
+        }
+    }
+    result
+}
+
+/// Evaluate the output paths of each output attribute of the flake in `args`, without building them
+///
+/// `args` are the [super::devour_flake::devour_flake] arguments.
+pub async fn evaluate_out_paths(
+    nixcmd: &NixCmd,
+    store: Option<&str>,
+    systems: &[System],
+    categories: &[OutputCategory],
+    args: &[String],
+) -> Result<BTreeMap<String, Vec<DrvOut>>> {
+    let dir = write_generated_flake(&attribution_flake(systems, categories))?;
+    let url = format!("path:{}#nixciOutPaths", dir.display());
+    let mut eval_args = vec![
+        "eval".to_string(),
+        url,
+        "--json".to_string(),
+        "--no-write-lock-file".to_string(),
+    ];
+    eval_args.extend(
+        super::store::store_args(store)
+            .into_iter()
+            .map(String::from),
+    );
+    eval_args.extend(attribution_args(args));
+    let eval_args: Vec<&str> = eval_args.iter().map(String::as_str).collect();
+    let all: BTreeMap<String, Vec<PathBuf>> =
+        nixcmd.run_with_args_expecting_json(&eval_args).await?;
+    Ok(all
+        .into_iter()
+        .map(|(attr, paths)| (attr, paths.into_iter().map(DrvOut).collect()))
+        .collect())
+}
+
+/// Map each output attribute of the flake in `args` to its output paths in `out_paths`
+///
+/// `args` are the [super::devour_flake::devour_flake] arguments used to build
+/// `out_paths`. Attributes whose outputs were not built are omitted.
+pub async fn attribute_outputs(
+    nixcmd: &NixCmd,
+    store: Option<&str>,
+    systems: &[System],
+    categories: &[OutputCategory],
+    args: &[String],
+    out_paths: &HashSet<DrvOut>,
+) -> Result<BTreeMap<String, Vec<DrvOut>>> {
+    let all = evaluate_out_paths(nixcmd, store, systems, categories, args).await?;
+    let by_attr = all
+        .into_iter()
+        .filter_map(|(attr, paths)| {
+            let paths: Vec<DrvOut> = paths
+                .into_iter()
+                .filter(|p| out_paths.contains(p))
+                .collect();
+            (!paths.is_empty()).then_some((attr, paths))
+        })
+        .collect();
+    Ok(by_attr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attribution_args() {
+        let args = [
+            "github:srid/haskell-flake?dir=test",
+            "--override-input",
+            "flake/haskell-flake",
+            ".",
+            "--override-input",
+            "systems",
+            "github:nix-systems/empty",
+            "--refresh",
+            "--impure",
+            "-o",
+            "result",
+            "--option",
+            "sandbox",
+            "false",
+            "--no-link",
+        ]
+        .map(String::from);
+        assert_eq!(
+            attribution_args(&args),
+            vec![
+                "--override-input",
+                "flake",
+                "github:srid/haskell-flake?dir=test",
+                "--override-input",
+                "flake/haskell-flake",
+                ".",
+                "--refresh",
+                "--impure",
+                "--option",
+                "sandbox",
+                "false",
+            ]
+        );
+    }
+}
@@ -0,0 +1,106 @@
+//! Remote builders, in the format of Nix's `builders` setting
+//!
+//! See <https://nix.dev/manual/nix/stable/advanced-topics/distributed-builds>
+use std::{path::Path, str::FromStr};
+
+use anyhow::{Context, Result};
+use nix_rs::flake::system::System;
+
+/// A remote builder, eg: `ssh://mac-mini aarch64-darwin`
+///
+/// Only the first two fields of the machine specification (the store URI and
+/// the comma-separated systems) are interpreted by nixci; the whole
+/// specification is passed on to Nix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Builder {
+    /// The full machine specification
+    pub spec: String,
+    /// The store URI of the builder
+    pub uri: String,
+    /// The systems the builder can build for
+    pub systems: Vec<System>,
+}
+
+impl FromStr for Builder {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Builder, String> {
+        let mut fields = s.split_whitespace();
+        let uri = fields
+            .next()
+            .ok_or_else(|| "empty builder specification".to_string())?;
+        let systems = match fields.next() {
+            Some("-") | None => vec![],
+            Some(systems) => systems.split(',').map(System::from).collect(),
+        };
+        Ok(Builder {
+            spec: s.trim().to_string(),
+            uri: uri.to_string(),
+            systems,
+        })
+    }
+}
+
+/// Parse the contents of a builders file (a Nix machines file)
+///
+/// Each non-empty line is a builder specification, and `#` starts a comment.
+pub fn parse_builders(contents: &str) -> Result<Vec<Builder>, String> {
+    contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(Builder::from_str)
+        .collect()
+}
+
+/// Read the builders in the given file, see [parse_builders]
+pub fn read_builders_file(path: &Path) -> Result<Vec<Builder>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Unable to read builders file {}", path.display()))?;
+    parse_builders(&contents)
+        .map_err(anyhow::Error::msg)
+        .with_context(|| format!("Invalid builders file {}", path.display()))
+}
+
+/// The value of Nix's `--builders` option for the given builders
+pub fn builders_arg(builders: &[&Builder]) -> String {
+    builders
+        .iter()
+        .map(|b| b.spec.as_str())
+        .collect::<Vec<_>>()
+        .join(" ; ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_builders() {
+        let builders = parse_builders(
+            "# Our builders\n\
+             ssh://mac-mini aarch64-darwin,x86_64-darwin /root/.ssh/id 4\n\
+             \n\
+             ssh-ng://linux-box - # any system\n",
+        )
+        .unwrap();
+        assert_eq!(
+            builders,
+            vec![
+                Builder {
+                    spec: "ssh://mac-mini aarch64-darwin,x86_64-darwin /root/.ssh/id 4".to_string(),
+                    uri: "ssh://mac-mini".to_string(),
+                    systems: vec!["aarch64-darwin".into(), "x86_64-darwin".into()],
+                },
+                Builder {
+                    spec: "ssh-ng://linux-box -".to_string(),
+                    uri: "ssh-ng://linux-box".to_string(),
+                    systems: vec![],
+                },
+            ]
+        );
+        assert_eq!(
+            builders_arg(&builders.iter().collect::<Vec<_>>()),
+            "ssh://mac-mini aarch64-darwin,x86_64-darwin /root/.ssh/id 4 ; ssh-ng://linux-box -"
+        );
+    }
+}
@@ -0,0 +1,102 @@
+//! Copy store paths to another store, using `nix copy`
+use std::path::PathBuf;
+
+use nix_rs::command::{CommandError, NixCmd};
+
+/// A store path that could not be copied
+#[derive(Debug)]
+pub struct CopyFailure {
+    pub path: PathBuf,
+    pub error: CommandError,
+}
+
+/// Copy the given paths (along with their runtime closure) from `store` (or
+/// the default store) to the store at `to`
+///
+/// All paths are copied in one go. If that fails because of some path (eg: an
+/// invalid or unsigned one), each path is copied individually, so as to return
+/// exactly those paths that failed to copy. Any other error (eg: the store at
+/// `to` being unreachable) is returned as is.
+pub async fn nix_copy(
+    cmd: &NixCmd,
+    store: Option<&str>,
+    to: &str,
+    paths: &[PathBuf],
+) -> Result<Vec<CopyFailure>, CommandError> {
+    if paths.is_empty() {
+        return Ok(vec![]);
+    }
+    match nix_copy_paths(cmd, store, to, paths).await {
+        Ok(()) => return Ok(vec![]),
+        Err(err) if !is_path_error(&err) => return Err(err),
+        Err(_) => {}
+    }
+    let mut failures = vec![];
+    for path in paths {
+        match nix_copy_paths(cmd, store, to, std::slice::from_ref(path)).await {
+            Ok(()) => {}
+            Err(error) if is_path_error(&error) => failures.push(CopyFailure {
+                path: path.clone(),
+                error,
+            }),
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(failures)
+}
+
+/// Whether `nix copy` failed because of a particular store path, rather than
+/// because of the stores themselves
+fn is_path_error(err: &CommandError) -> bool {
+    match err {
+        CommandError::ProcessFailed {
+            stderr: Some(stderr),
+            ..
+        } => stderr
+            .lines()
+            .any(|line| line.contains("error:") && line.contains("/nix/store/")),
+        _ => false,
+    }
+}
+
+async fn nix_copy_paths(
+    cmd: &NixCmd,
+    store: Option<&str>,
+    to: &str,
+    paths: &[PathBuf],
+) -> Result<(), CommandError> {
+    let paths: Vec<String> = paths
+        .iter()
+        .map(|p| p.to_string_lossy().to_string())
+        .collect();
+    let mut args = vec!["copy", "--to", to];
+    args.extend(super::store::store_args(store));
+    args.extend(paths.iter().map(String::as_str));
+    cmd.run_with_args_returning_stdout(&args).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_path_error() {
+        let failed = |stderr: &str| CommandError::ProcessFailed {
+            stderr: Some(stderr.to_string()),
+            exit_code: Some(1),
+        };
+        assert!(is_path_error(&failed(
+            "error: path '/nix/store/a-foo' is not valid\n"
+        )));
+        assert!(is_path_error(&failed(
+            "error: cannot add path '/nix/store/a-foo' because it lacks a signature by a trusted key\n"
+        )));
+        assert!(!is_path_error(&failed(
+            "error: cannot connect to 'builder': ssh: Could not resolve hostname builder\n"
+        )));
+        assert!(!is_path_error(&failed(
+            "copying path '/nix/store/a-foo' to 's3://bucket'...\nerror: AWS error: Access Denied\n"
+        )));
+    }
+}
@@ -2,8 +2,19 @@
 
 use anyhow::{bail, Context, Result};
 use nix_rs::command::NixCmd;
-use std::{collections::HashSet, path::PathBuf, process::Stdio, str::FromStr};
-use tokio::io::{AsyncBufReadExt, BufReader};
+use serde::{Deserialize, Serialize, Serializer};
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet, HashSet},
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    process::Stdio,
+    str::FromStr,
+};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    process::ChildStderr,
+    task::JoinHandle,
+};
 
 use super::nix_store::DrvOut;
 
@@ -12,32 +23,260 @@ use super::nix_store::DrvOut;
 /// We expect this environment to be set in Nix build and shell.
 pub const DEVOUR_FLAKE: &str = env!("DEVOUR_FLAKE");
 
-pub struct DevourFlakeOutput(pub HashSet<DrvOut>);
+/// A category of flake outputs built by devour-flake
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    clap::ValueEnum,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum OutputCategory {
+    #[value(name = "packages")]
+    Packages,
+    #[value(name = "apps")]
+    Apps,
+    #[value(name = "checks")]
+    Checks,
+    #[value(name = "devShells")]
+    DevShells,
+    #[value(name = "nixosConfigurations")]
+    NixosConfigurations,
+    #[value(name = "darwinConfigurations")]
+    DarwinConfigurations,
+    /// `legacyPackages.${system}.homeConfigurations`
+    #[value(name = "homeConfigurations")]
+    HomeConfigurations,
+}
+
+impl OutputCategory {
+    pub const ALL: [OutputCategory; 7] = [
+        OutputCategory::Packages,
+        OutputCategory::Apps,
+        OutputCategory::Checks,
+        OutputCategory::DevShells,
+        OutputCategory::NixosConfigurations,
+        OutputCategory::DarwinConfigurations,
+        OutputCategory::HomeConfigurations,
+    ];
+
+    /// The top-level flake output attribute containing this category
+    pub fn flake_output_attr(&self) -> &'static str {
+        match self {
+            OutputCategory::Packages => "packages",
+            OutputCategory::Apps => "apps",
+            OutputCategory::Checks => "checks",
+            OutputCategory::DevShells => "devShells",
+            OutputCategory::NixosConfigurations => "nixosConfigurations",
+            OutputCategory::DarwinConfigurations => "darwinConfigurations",
+            OutputCategory::HomeConfigurations => "legacyPackages",
+        }
+    }
+}
+
+/// Which [OutputCategory]s to build
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OutputFilter {
+    /// Build only these categories; `None` means all of them.
+    pub include: Option<BTreeSet<OutputCategory>>,
+    /// Do not build these categories
+    pub exclude: BTreeSet<OutputCategory>,
+}
+
+impl OutputFilter {
+    /// Create a filter from include and exclude lists, with an empty include
+    /// list meaning all categories.
+    pub fn new(include: &[OutputCategory], exclude: &[OutputCategory]) -> Self {
+        OutputFilter {
+            include: (!include.is_empty()).then(|| include.iter().cloned().collect()),
+            exclude: exclude.iter().cloned().collect(),
+        }
+    }
+
+    /// Combine two filters, such that only categories selected by both are built
+    pub fn and(&self, other: &OutputFilter) -> Self {
+        let include = match (&self.include, &other.include) {
+            (Some(a), Some(b)) => Some(a.intersection(b).cloned().collect()),
+            (a, b) => a.clone().or_else(|| b.clone()),
+        };
+        OutputFilter {
+            include,
+            exclude: self.exclude.union(&other.exclude).cloned().collect(),
+        }
+    }
+
+    /// The categories selected by this filter
+    pub fn categories(&self) -> Vec<OutputCategory> {
+        OutputCategory::ALL
+            .into_iter()
+            .filter(|c| self.include.as_ref().map_or(true, |i| i.contains(c)))
+            .filter(|c| !self.exclude.contains(c))
+            .collect()
+    }
+
+    /// Whether this filter selects all categories
+    pub fn is_all(&self) -> bool {
+        self.categories().len() == OutputCategory::ALL.len()
+    }
+
+    /// Create a flake re-exporting only the selected outputs of its `flake` input
+    ///
+    /// devour-flake builds all outputs of its `flake` input, so we point that
+    /// input to this flake (and its `flake` input to the user's flake) to
+    /// build only the selected categories.
+    fn create_wrapper_flake(&self) -> Result<PathBuf> {
+        let attrs = self
+            .categories()
+            .iter()
+            .map(|c| format!("{} = null;", c.flake_output_attr()))
+            .collect::<Vec<_>>()
+            .join(" ");
+        write_generated_flake(&format!(
+            r#"# Generated by nixci, to build only some outputs of a flake
+{{
+  inputs.flake = {{ }};
+  outputs = inputs: builtins.intersectAttrs {{ {} }} inputs.flake;
+}}
+"#,
+            attrs
+        ))
+    }
+}
+
+/// Write a `flake.nix` generated by nixci to the cache directory, returning the directory
+///
+/// The directory is named after the hash of the contents, so that it can be
+/// reused across runs (and by concurrent builds). It lives in the private
+/// per-user [crate::cache::cache_dir], so other users cannot tamper with it.
+pub(crate) fn write_generated_flake(flake_nix: &str) -> Result<PathBuf> {
+    write_generated_files(&[("flake.nix", flake_nix)])
+}
+
+/// Like [write_generated_flake], but for any set of (file name, contents) pairs
+pub(crate) fn write_generated_files(files: &[(&str, &str)]) -> Result<PathBuf> {
+    let mut hasher = DefaultHasher::new();
+    files.hash(&mut hasher);
+    let dir = crate::cache::cache_dir("flakes")?.join(format!("{:016x}", hasher.finish()));
+    crate::cache::private_dir(&dir)?;
+    files
+        .iter()
+        .try_for_each(|(name, contents)| std::fs::write(dir.join(name), contents))
+        .with_context(|| format!("Unable to create flake in {}", dir.display()))?;
+    Ok(dir)
+}
+
+/// Point devour-flake's `flake` input to the wrapper flake at `wrapper_dir`
+///
+/// `args` are the [devour_flake] arguments, starting with the user's flake URL.
+fn wrap_flake_args(args: Vec<String>, wrapper_dir: &Path) -> Vec<String> {
+    let mut args = args.into_iter();
+    let mut wrapped = vec![format!("path:{}", wrapper_dir.display())];
+    if let Some(url) = args.next() {
+        wrapped.extend([
+            "--override-input".to_string(),
+            "flake/flake".to_string(),
+            url,
+        ]);
+    }
+    while let Some(arg) = args.next() {
+        let is_override = arg == "--override-input";
+        wrapped.push(arg);
+        if is_override {
+            // The user's flake is now an input of the wrapper flake
+            if let Some(input) = args.next() {
+                match input.strip_prefix("flake/") {
+                    Some(input) => wrapped.push(format!("flake/flake/{}", input)),
+                    None => wrapped.push(input),
+                }
+            }
+        }
+    }
+    wrapped
+}
+
+/// The outputs built by devour-flake
+#[derive(Debug, Default, Serialize)]
+pub struct DevourFlakeOutput {
+    /// The built output paths
+    #[serde(rename = "outputs", serialize_with = "serialize_sorted")]
+    pub out_paths: HashSet<DrvOut>,
+
+    /// The built output paths of each flake output attribute, eg:
+    /// `packages.x86_64-linux.foo`
+    ///
+    /// This is empty unless explicitly populated using [super::attribution].
+    #[serde(rename = "outputsByAttr")]
+    pub by_attr: BTreeMap<String, Vec<DrvOut>>,
+}
+
+/// Serialize as a sorted list, so that the output is deterministic
+fn serialize_sorted<S>(outs: &HashSet<DrvOut>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let mut outs: Vec<&DrvOut> = outs.iter().collect();
+    outs.sort();
+    outs.serialize(serializer)
+}
 
 impl FromStr for DevourFlakeOutput {
     type Err = anyhow::Error;
 
     fn from_str(output_filename: &str) -> Result<Self, Self::Err> {
-        // Read output_filename, as newline separated strings
+        DevourFlakeOutput::read(output_filename, false)
+    }
+}
+
+impl DevourFlakeOutput {
+    /// Read the outpath of devour-flake, containing the newline separated outputs
+    ///
+    /// An outpath with no outputs is an error, unless `allow_empty` is set (eg:
+    /// when only some categories of outputs, which the flake may not have,
+    /// are built).
+    fn read(output_filename: &str, allow_empty: bool) -> Result<Self> {
         let raw_output = std::fs::read_to_string(output_filename)?;
         let outs = raw_output.split_ascii_whitespace();
         let outs: HashSet<DrvOut> = outs.map(|s| DrvOut(PathBuf::from(s))).collect();
-        if outs.is_empty() {
+        if outs.is_empty() && !allow_empty {
             bail!(
                 "devour-flake produced an outpath ({}) with no outputs",
                 output_filename
             );
         } else {
-            Ok(DevourFlakeOutput(outs))
+            Ok(DevourFlakeOutput {
+                out_paths: outs,
+                ..DevourFlakeOutput::default()
+            })
         }
     }
 }
 
+/// Run devour-flake with the given `nix build` arguments, building into `store`
+/// (or the default store)
+///
+/// Only the outputs selected by `outputs` are built. If `log_prefix` is set,
+/// each line of the build log is prefixed with it, so that the logs of
+/// concurrent builds can be told apart.
 pub async fn devour_flake(
     nixcmd: &NixCmd,
+    store: Option<&str>,
     verbose: bool,
+    log_prefix: Option<String>,
+    outputs: &OutputFilter,
     args: Vec<String>,
 ) -> Result<DevourFlakeOutput> {
+    let args = if outputs.is_all() {
+        args
+    } else {
+        wrap_flake_args(args, &outputs.create_wrapper_flake()?)
+    };
     // TODO: Use nix_rs here as well
     // In the context of doing https://github.com/srid/nixci/issues/15
     let devour_flake_url = format!("{}#default", env!("DEVOUR_FLAKE"));
@@ -48,15 +287,38 @@ pub async fn devour_flake(
         "-L",
         "--no-link",
         "--print-out-paths",
-        "--override-input",
-        "flake",
     ])
+    .args(super::store::store_args(store))
+    .args(["--override-input", "flake"])
     .args(args);
     nix_rs::command::trace_cmd(&cmd);
     let mut output_fut = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
-    let stderr_handle = output_fut.stderr.take().unwrap();
+    forward_stderr(output_fut.stderr.take().unwrap(), verbose, log_prefix);
+    let output = output_fut
+        .wait_with_output()
+        .await
+        .context("Unable to spawn devour-flake process")?;
+    if output.status.success() {
+        let stdout = String::from_utf8(output.stdout)?;
+        // The flake may have none of the selected outputs
+        let v = DevourFlakeOutput::read(stdout.trim(), !outputs.is_all())?;
+        Ok(v)
+    } else {
+        let exit_code = output.status.code().unwrap_or(1);
+        bail!("devour-flake failed to run (exited: {})", exit_code);
+    }
+}
+
+/// Print the `stderr` of a Nix process line by line, prefixed with `log_prefix` if set
+///
+/// Unless `verbose`, the noise caused by overriding inputs is left out.
+pub fn forward_stderr(
+    stderr: ChildStderr,
+    verbose: bool,
+    log_prefix: Option<String>,
+) -> JoinHandle<()> {
     tokio::spawn(async move {
-        let mut reader = BufReader::new(stderr_handle).lines();
+        let mut reader = BufReader::new(stderr).lines();
         while let Some(line) = reader.next_line().await.expect("read stderr") {
             if !verbose {
                 if line.starts_with("• Added input") {
@@ -67,21 +329,12 @@ pub async fn devour_flake(
                     continue;
                 }
             }
-            eprintln!("{}", line);
+            match &log_prefix {
+                Some(prefix) => eprintln!("{} {}", prefix, line),
+                None => eprintln!("{}", line),
+            }
         }
-    });
-    let output = output_fut
-        .wait_with_output()
-        .await
-        .context("Unable to spawn devour-flake process")?;
-    if output.status.success() {
-        let stdout = String::from_utf8(output.stdout)?;
-        let v = DevourFlakeOutput::from_str(stdout.trim())?;
-        Ok(v)
-    } else {
-        let exit_code = output.status.code().unwrap_or(1);
-        bail!("devour-flake failed to run (exited: {})", exit_code);
-    }
+    })
 }
 
 /// Transform `--override-input` arguments to use `flake/` prefix, which
@@ -97,3 +350,68 @@ pub fn transform_override_inputs(args: &mut [String]) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_filter() {
+        let pr = OutputFilter::new(&[], &[OutputCategory::NixosConfigurations]);
+        let fast = OutputFilter::new(&[OutputCategory::Checks], &[]);
+        assert!(OutputFilter::default().is_all());
+        assert_eq!(pr.categories().len(), OutputCategory::ALL.len() - 1);
+        assert_eq!(pr.and(&fast).categories(), vec![OutputCategory::Checks]);
+        let both = OutputFilter::new(&[OutputCategory::Checks, OutputCategory::Packages], &[]);
+        assert_eq!(both.and(&fast).categories(), vec![OutputCategory::Checks]);
+        let none = OutputFilter::new(&[], &[OutputCategory::Checks]);
+        assert!(none.and(&fast).categories().is_empty());
+    }
+
+    #[test]
+    fn test_read_devour_flake_output() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("nixci-test-devour-{}", std::process::id()));
+        let path_str = path.to_string_lossy().to_string();
+        std::fs::write(&path, "/nix/store/a-foo\n/nix/store/b-bar\n")?;
+        assert_eq!(DevourFlakeOutput::from_str(&path_str)?.out_paths.len(), 2);
+        std::fs::write(&path, "")?;
+        assert!(DevourFlakeOutput::from_str(&path_str).is_err());
+        assert!(DevourFlakeOutput::read(&path_str, true)?
+            .out_paths
+            .is_empty());
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_wrap_flake_args() {
+        let args = [
+            "github:srid/haskell-flake?dir=test",
+            "--override-input",
+            "flake/haskell-flake",
+            ".",
+            "--override-input",
+            "systems",
+            "github:nix-systems/empty",
+            "--refresh",
+        ]
+        .map(String::from)
+        .to_vec();
+        assert_eq!(
+            wrap_flake_args(args, Path::new("/tmp/wrapper")),
+            vec![
+                "path:/tmp/wrapper",
+                "--override-input",
+                "flake/flake",
+                "github:srid/haskell-flake?dir=test",
+                "--override-input",
+                "flake/flake/haskell-flake",
+                ".",
+                "--override-input",
+                "systems",
+                "github:nix-systems/empty",
+                "--refresh",
+            ]
+        );
+    }
+}
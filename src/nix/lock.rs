@@ -4,11 +4,26 @@ use anyhow::{bail, Result};
 use nix_rs::{command::NixCmd, flake::url::FlakeUrl};
 
 /// Make sure that the `flake.lock` file is in sync.
-pub async fn nix_flake_lock_check(nixcmd: &NixCmd, url: &FlakeUrl) -> Result<()> {
+///
+/// Its log is printed like that of [super::devour_flake::devour_flake],
+/// prefixed with `log_prefix` if set.
+pub async fn nix_flake_lock_check(
+    nixcmd: &NixCmd,
+    store: Option<&str>,
+    verbose: bool,
+    log_prefix: Option<String>,
+    url: &FlakeUrl,
+) -> Result<()> {
     let mut cmd = nixcmd.command();
-    cmd.args(["flake", "lock", "--no-update-lock-file", &url.0]);
+    cmd.args(["flake", "lock", "--no-update-lock-file", &url.0])
+        .args(super::store::store_args(store));
     nix_rs::command::trace_cmd(&cmd);
-    let status = cmd.stdin(Stdio::null()).spawn()?.wait().await?;
+    let mut child = cmd.stdin(Stdio::null()).stderr(Stdio::piped()).spawn()?;
+    let stderr =
+        super::devour_flake::forward_stderr(child.stderr.take().unwrap(), verbose, log_prefix);
+    let status = child.wait().await?;
+    // Print the whole log before reporting the outcome
+    stderr.await?;
     if status.success() {
         Ok(())
     } else {
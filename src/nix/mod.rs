@@ -1,4 +1,10 @@
+pub mod attribution;
+pub mod builders;
+pub mod copy;
 pub mod devour_flake;
 pub mod lock;
 pub mod nix_store;
+pub mod path_info;
+pub mod store;
+pub mod substituter;
 pub mod system_list;
@@ -1,16 +1,17 @@
 /// Run `nix-store` in Rust
 ///
 /// TODO: Upstream this to nix-rs
-use std::{fmt, path::PathBuf};
+use std::{collections::BTreeSet, fmt, future::Future, path::PathBuf};
 
 use anyhow::Result;
+use futures::{stream, StreamExt};
 use nix_rs::command::{CommandError, NixCmdError};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::process::Command;
 
 /// Nix derivation output path
-#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Clone, Hash)]
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Clone, Hash, Serialize)]
 pub struct DrvOut(pub PathBuf);
 
 impl DrvOut {
@@ -31,7 +32,8 @@ pub enum StorePath {
 
 impl StorePath {
     pub fn new(path: PathBuf) -> Self {
-        if path.ends_with(".drv") {
+        // Not `Path::ends_with`, which compares whole path components.
+        if path.extension().is_some_and(|ext| ext == "drv") {
             StorePath::Drv(path)
         } else {
             StorePath::Other(path)
@@ -52,97 +54,329 @@ impl fmt::Display for StorePath {
     }
 }
 
+/// Which dependencies of build outputs to fetch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ClosureKind {
+    /// The runtime closure of the outputs
+    Runtime,
+    /// The derivations of the outputs, and everything needed to build them
+    Build,
+    /// Both build-time and runtime dependencies
+    #[default]
+    All,
+}
+
+/// The commands used to query the Nix store
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum StoreBackend {
+    /// `nix-store` (see [NixStoreCmd])
+    #[default]
+    Legacy,
+    /// `nix path-info` and `nix derivation show`, respecting the global `nix` options
+    /// (see [super::path_info::NixPathInfoCmd])
+    Modern,
+}
+
+/// Dependencies fetched by [NixStoreCmd::fetch_deps]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Dependencies {
+    /// The dependencies, in sorted order
+    pub paths: Vec<StorePath>,
+    /// The outputs without a valid deriver in the Nix store
+    ///
+    /// Such outputs are typically substituted from a cache. Only their
+    /// runtime closure is included in [Dependencies::paths].
+    pub without_deriver: Vec<PathBuf>,
+}
+
 /// The `nix-store` command
 /// See documentation for [nix-store](https://nixos.org/manual/nix/stable/command-ref/nix-store.html)
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Hash)]
-pub struct NixStoreCmd;
+pub struct NixStoreCmd {
+    /// The `nix-store` executable to run
+    pub program: PathBuf,
+    /// Maximum number of paths to pass to a single `nix-store` invocation
+    pub batch_size: usize,
+    /// Number of `nix-store` invocations to run concurrently
+    pub jobs: usize,
+    /// The store to query, instead of the default one
+    pub store: Option<String>,
+}
+
+impl Default for NixStoreCmd {
+    fn default() -> Self {
+        NixStoreCmd {
+            program: PathBuf::from("nix-store"),
+            batch_size: 256,
+            jobs: 4,
+            store: None,
+        }
+    }
+}
 
 impl NixStoreCmd {
     pub fn command(&self) -> Command {
-        let mut cmd = Command::new("nix-store");
+        let mut cmd = Command::new(&self.program);
         cmd.kill_on_drop(true);
+        cmd.args(super::store::store_args(self.store.as_deref()));
         cmd
     }
 }
 
 impl NixStoreCmd {
-    /// Fetch all build and runtime dependencies of given [devour_flake::DrvOut]s
+    /// Fetch the dependencies of given [DrvOut]s selected by [ClosureKind]
+    pub async fn fetch_deps(
+        &self,
+        closure: ClosureKind,
+        out_paths: Vec<DrvOut>,
+    ) -> Result<Dependencies, NixStoreCmdError> {
+        match closure {
+            ClosureKind::Runtime => self.fetch_runtime_deps(out_paths).await,
+            ClosureKind::Build => self.fetch_build_deps(out_paths).await,
+            ClosureKind::All => self.fetch_all_deps(out_paths).await,
+        }
+    }
+
+    /// Fetch the runtime dependencies of given [DrvOut]s, including the outputs themselves
+    ///
+    /// The result contains only [StorePath::Other] paths.
+    pub async fn fetch_runtime_deps(
+        &self,
+        out_paths: Vec<DrvOut>,
+    ) -> Result<Dependencies, NixStoreCmdError> {
+        let out_paths = dedup_out_paths(out_paths);
+        let all_outs: BTreeSet<StorePath> = self
+            .in_batches(&out_paths, |batch| self.nix_store_query_requisites(batch))
+            .await?;
+        Ok(Dependencies {
+            paths: all_outs.into_iter().collect(),
+            without_deriver: vec![],
+        })
+    }
+
+    /// Fetch the build-time dependencies of given [DrvOut]s
+    ///
+    /// This is the derivers of the outputs, along with the closure (including
+    /// outputs) of their inputs. The outputs themselves are not included,
+    /// except for those without a valid deriver (see [Dependencies::without_deriver]).
+    pub async fn fetch_build_deps(
+        &self,
+        out_paths: Vec<DrvOut>,
+    ) -> Result<Dependencies, NixStoreCmdError> {
+        let out_paths = dedup_out_paths(out_paths);
+        let (all_drvs, without_deriver) = self.query_valid_derivers(&out_paths).await?;
+        let inputs: BTreeSet<StorePath> = self
+            .in_batches(&all_drvs, |batch| self.nix_store_query_references(batch))
+            .await?;
+        let inputs: Vec<PathBuf> = inputs.iter().map(|p| p.as_path().clone()).collect();
+        let mut all_outs: BTreeSet<StorePath> = self
+            .in_batches(&inputs, |batch| {
+                self.nix_store_query_requisites_with_outputs(batch)
+            })
+            .await?;
+        all_outs.extend(all_drvs.into_iter().map(StorePath::Drv));
+        all_outs.extend(
+            self.in_batches(&without_deriver, |batch| {
+                self.nix_store_query_requisites(batch)
+            })
+            .await?,
+        );
+        Ok(Dependencies {
+            paths: all_outs.into_iter().collect(),
+            without_deriver,
+        })
+    }
+
+    /// Fetch all build and runtime dependencies of given [DrvOut]s
     ///
-    /// This is done by querying the deriver of each output path from [devour_flake::DrvOut] using [nix_store_query_deriver] and
-    /// then querying all dependencies of each deriver using [nix_store_query_requisites_with_outputs].
-    /// Finally, all dependencies of each deriver are collected and returned as [Vec<StorePath>].
+    /// This is done by querying the valid derivers of the output paths using [NixStoreCmd::nix_store_query_valid_derivers]
+    /// and then querying all dependencies of those derivers using [NixStoreCmd::nix_store_query_requisites_with_outputs].
+    /// Outputs without a valid deriver, such as those substituted from a cache, contribute
+    /// only their runtime closure (see [Dependencies::without_deriver]).
+    ///
+    /// Paths are passed to `nix-store` in batches of [NixStoreCmd::batch_size], with up to
+    /// [NixStoreCmd::jobs] batches queried concurrently. The result is deduplicated and sorted.
     pub async fn fetch_all_deps(
         &self,
         out_paths: Vec<DrvOut>,
+    ) -> Result<Dependencies, NixStoreCmdError> {
+        let out_paths = dedup_out_paths(out_paths);
+        let (all_drvs, without_deriver) = self.query_valid_derivers(&out_paths).await?;
+        let mut all_outs: BTreeSet<StorePath> = self
+            .in_batches(&all_drvs, |batch| {
+                self.nix_store_query_requisites_with_outputs(batch)
+            })
+            .await?;
+        all_outs.extend(
+            self.in_batches(&without_deriver, |batch| {
+                self.nix_store_query_requisites(batch)
+            })
+            .await?,
+        );
+        Ok(Dependencies {
+            paths: all_outs.into_iter().collect(),
+            without_deriver,
+        })
+    }
+
+    /// Return the total NAR size, in bytes, of the runtime closure of the given paths
+    pub async fn closure_size(&self, paths: &[PathBuf]) -> Result<u64, NixStoreCmdError> {
+        let closure: BTreeSet<StorePath> = self
+            .in_batches(paths, |batch| self.nix_store_query_requisites(batch))
+            .await?;
+        let closure: Vec<PathBuf> = closure.iter().map(|p| p.as_path().clone()).collect();
+        let sizes = in_batches(&closure, self.batch_size, self.jobs, |batch| {
+            self.nix_store_query_sizes(batch)
+        })
+        .await?;
+        Ok(sizes.into_iter().sum())
+    }
+
+    /// Return the valid derivers of the given build outputs, along with the
+    /// outputs that have none.
+    async fn query_valid_derivers(
+        &self,
+        out_paths: &[PathBuf],
+    ) -> Result<(Vec<PathBuf>, Vec<PathBuf>), NixStoreCmdError> {
+        let drvs: Vec<PathBuf> = self
+            .in_batches(out_paths, |batch| {
+                self.nix_store_query_valid_derivers(batch)
+            })
+            .await?
+            .into_iter()
+            .collect();
+        // `--valid-derivers` does not say which output each deriver belongs
+        // to, so find the outputs covered by them.
+        let covered: BTreeSet<PathBuf> = self
+            .in_batches(&drvs, |batch| self.nix_store_query_outputs(batch))
+            .await?;
+        let without_deriver = out_paths
+            .iter()
+            .filter(|out_path| !covered.contains(*out_path))
+            .cloned()
+            .collect();
+        Ok((drvs, without_deriver))
+    }
+
+    /// Run `query` on batches of `paths` concurrently, collecting their results
+    async fn in_batches<'a, T, F, Fut>(
+        &self,
+        paths: &'a [PathBuf],
+        query: F,
+    ) -> Result<BTreeSet<T>, NixStoreCmdError>
+    where
+        T: Ord,
+        F: Fn(&'a [PathBuf]) -> Fut,
+        Fut: Future<Output = Result<Vec<T>, NixStoreCmdError>>,
+    {
+        let all = in_batches(paths, self.batch_size, self.jobs, query).await?;
+        Ok(all.into_iter().collect())
+    }
+
+    /// Return the valid derivations that can build the given build outputs.
+    ///
+    /// Unlike `--deriver`, which returns only the recorded deriver (that may
+    /// have been garbage collected), this returns any valid derivation
+    /// producing an output.
+    async fn nix_store_query_valid_derivers(
+        &self,
+        out_paths: &[PathBuf],
+    ) -> Result<Vec<PathBuf>, NixStoreCmdError> {
+        let mut cmd = self.command();
+        cmd.args(["--query", "--valid-derivers"]).args(out_paths);
+        let lines = self.run_returning_lines(cmd).await?;
+        Ok(lines.into_iter().map(PathBuf::from).collect())
+    }
+
+    /// Return the outputs of the given derivations.
+    async fn nix_store_query_outputs(
+        &self,
+        drv_paths: &[PathBuf],
+    ) -> Result<Vec<PathBuf>, NixStoreCmdError> {
+        let mut cmd = self.command();
+        cmd.args(["--query", "--outputs"]).args(drv_paths);
+        let lines = self.run_returning_lines(cmd).await?;
+        Ok(lines.into_iter().map(PathBuf::from).collect())
+    }
+
+    /// Return the runtime closures of the given build outputs.
+    async fn nix_store_query_requisites(
+        &self,
+        out_paths: &[PathBuf],
     ) -> Result<Vec<StorePath>, NixStoreCmdError> {
-        let mut all_drvs = Vec::new();
-        for out in out_paths.iter() {
-            let DrvOut(out_path) = out;
-            let drv = self.nix_store_query_deriver(out_path.clone()).await?;
-            all_drvs.push(drv);
-        }
-        let mut all_outs = Vec::new();
-        for drv in all_drvs {
-            let deps = self
-                .nix_store_query_requisites_with_outputs(drv.clone())
-                .await?;
-            all_outs.extend(deps.into_iter());
-        }
-        Ok(all_outs)
+        self.nix_store_query(&["--requisites"], out_paths).await
     }
 
-    /// Return the derivation used to build the given build output.
-    async fn nix_store_query_deriver(&self, out_path: PathBuf) -> Result<DrvOut, NixStoreCmdError> {
+    /// Return the NAR size, in bytes, of each of the given paths.
+    async fn nix_store_query_sizes(&self, paths: &[PathBuf]) -> Result<Vec<u64>, NixStoreCmdError> {
         let mut cmd = self.command();
-        cmd.args([
-            "--query",
-            "--valid-derivers",
-            &out_path.to_string_lossy().as_ref(),
-        ]);
-        nix_rs::command::trace_cmd(&cmd);
-        let out = cmd.output().await?;
-        if out.status.success() {
-            let drv_path = String::from_utf8(out.stdout)?.trim().to_string();
-            if drv_path == "unknown-deriver" {
-                return Err(NixStoreCmdError::UnknownDeriver);
-            }
-            Ok(DrvOut(PathBuf::from(drv_path)))
-        } else {
-            // TODO(refactor): When upstreaming this module to nix-rs, create a
-            // nicer and unified way to create `ProcessFailed`
-            let stderr = Some(String::from_utf8_lossy(&out.stderr).to_string());
-            let exit_code = out.status.code();
-            Err(CommandError::ProcessFailed { stderr, exit_code }.into())
-        }
+        cmd.args(["--query", "--size"]).args(paths);
+        let lines = self.run_returning_lines(cmd).await?;
+        lines
+            .iter()
+            .map(|l| {
+                l.parse().map_err(|_| {
+                    CommandError::ProcessFailed {
+                        stderr: Some(format!(
+                            "Unexpected `nix-store --query --size` output: {}",
+                            l
+                        )),
+                        exit_code: None,
+                    }
+                    .into()
+                })
+            })
+            .collect()
+    }
+
+    /// Return the immediate inputs (derivations and sources) of the given [StorePath::Drv]s.
+    async fn nix_store_query_references(
+        &self,
+        drv_paths: &[PathBuf],
+    ) -> Result<Vec<StorePath>, NixStoreCmdError> {
+        self.nix_store_query(&["--references"], drv_paths).await
     }
 
-    /// Given a [StorePath::Drv], this function recursively queries and return all
-    /// of its dependencies in the Nix store.
+    /// Given [StorePath::Drv]s, this function recursively queries and return all
+    /// of their dependencies in the Nix store.
     async fn nix_store_query_requisites_with_outputs(
         &self,
-        drv_path: DrvOut,
+        drv_paths: &[PathBuf],
+    ) -> Result<Vec<StorePath>, NixStoreCmdError> {
+        self.nix_store_query(&["--requisites", "--include-outputs"], drv_paths)
+            .await
+    }
+
+    /// Run `nix-store --query` with the given flags on `paths`, returning the store paths printed.
+    async fn nix_store_query(
+        &self,
+        flags: &[&str],
+        paths: &[PathBuf],
     ) -> Result<Vec<StorePath>, NixStoreCmdError> {
         let mut cmd = self.command();
-        cmd.args([
-            "--query",
-            "--requisites",
-            "--include-outputs",
-            &drv_path.0.to_string_lossy().as_ref(),
-        ]);
+        cmd.arg("--query").args(flags).args(paths);
+        let lines = self.run_returning_lines(cmd).await?;
+        Ok(lines
+            .into_iter()
+            .map(PathBuf::from)
+            .map(StorePath::new)
+            .collect())
+    }
+
+    /// Run the given `nix-store` command, returning the non-empty lines of its stdout
+    async fn run_returning_lines(&self, mut cmd: Command) -> Result<Vec<String>, NixStoreCmdError> {
         nix_rs::command::trace_cmd(&cmd);
         let out = cmd.output().await?;
         if out.status.success() {
             let out = String::from_utf8(out.stdout)?;
-            let out = out
+            Ok(out
                 .lines()
                 .map(|l| l.trim().to_string())
                 .filter(|l| !l.is_empty())
-                .map(PathBuf::from)
-                .map(StorePath::new)
-                .collect();
-            Ok(out)
+                .collect())
         } else {
-            // TODO(refactor): see above
+            // TODO(refactor): When upstreaming this module to nix-rs, create a
+            // nicer and unified way to create `ProcessFailed`
             let stderr = Some(String::from_utf8_lossy(&out.stderr).to_string());
             let exit_code = out.status.code();
             Err(CommandError::ProcessFailed { stderr, exit_code }.into())
@@ -150,14 +384,43 @@ impl NixStoreCmd {
     }
 }
 
+/// Run `query` on batches of at most `batch_size` paths, `jobs` of them
+/// concurrently, concatenating their results in no particular order
+pub(crate) async fn in_batches<'a, T, E, F, Fut>(
+    paths: &'a [PathBuf],
+    batch_size: usize,
+    jobs: usize,
+    query: F,
+) -> Result<Vec<T>, E>
+where
+    F: Fn(&'a [PathBuf]) -> Fut,
+    Fut: Future<Output = Result<Vec<T>, E>>,
+{
+    let mut results = stream::iter(paths.chunks(batch_size.max(1)))
+        .map(query)
+        .buffer_unordered(jobs.max(1));
+    let mut all = vec![];
+    while let Some(result) = results.next().await {
+        all.extend(result?);
+    }
+    Ok(all)
+}
+
+/// Sort and deduplicate the given output paths
+fn dedup_out_paths(out_paths: Vec<DrvOut>) -> Vec<PathBuf> {
+    out_paths
+        .into_iter()
+        .map(|DrvOut(p)| p)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
 /// `nix-store` command errors
 #[derive(Error, Debug)]
 pub enum NixStoreCmdError {
     #[error(transparent)]
     NixCmdError(#[from] NixCmdError),
-
-    #[error("Unknown deriver")]
-    UnknownDeriver,
 }
 
 impl From<std::io::Error> for NixStoreCmdError {
@@ -180,3 +443,181 @@ impl From<CommandError> for NixStoreCmdError {
         nixcmd_error.into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// A fake `nix-store` logging each invocation to `calls.log` in `dir`
+    ///
+    /// The deriver of `/nix/store/out-<n>` is `/nix/store/out-<n>.drv`, whose
+    /// inputs are `glibc.drv` and `gcc.drv`. Only `glibc` is needed at runtime.
+    /// `/nix/store/cached-<n>` has an unknown deriver, and the deriver of
+    /// `/nix/store/gced-<n>` is not valid.
+    fn fake_nix_store(dir: &std::path::Path) -> PathBuf {
+        let script = format!(
+            r#"#!/bin/sh
+echo "$@" >> {log}
+mode="$2"; shift 2
+outputs=no
+if [ "$1" = --include-outputs ]; then outputs=yes; shift; fi
+for p in "$@"; do
+  case "$mode" in
+    --valid-derivers)
+      case "$p" in
+        /nix/store/cached-* | /nix/store/gced-*) ;;
+        *) echo "$p.drv" ;;
+      esac ;;
+    --outputs) echo "${{p%.drv}}" ;;
+    --references) echo /nix/store/glibc.drv; echo /nix/store/gcc.drv ;;
+    --size) echo 100 ;;
+    --requisites)
+      echo "$p"
+      case "$p" in
+        /nix/store/out-*.drv)
+          echo /nix/store/glibc.drv; echo /nix/store/gcc.drv
+          if [ $outputs = yes ]; then echo "${{p%.drv}}"; echo /nix/store/glibc; echo /nix/store/gcc; fi ;;
+        *.drv) if [ $outputs = yes ]; then echo "${{p%.drv}}"; fi ;;
+        /nix/store/out-*) echo /nix/store/glibc ;;
+      esac ;;
+  esac
+done
+"#,
+            log = dir.join("calls.log").display(),
+        );
+        let program = dir.join("nix-store");
+        std::fs::write(&program, script).unwrap();
+        std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+        program
+    }
+
+    /// 1000 distinct outputs, each listed twice
+    fn many_outs() -> Vec<DrvOut> {
+        (0..2000)
+            .map(|i| DrvOut(PathBuf::from(format!("/nix/store/out-{}", i % 1000))))
+            .collect()
+    }
+
+    /// Run [NixStoreCmd::fetch_deps] on `outs` against [fake_nix_store],
+    /// returning the result and the number of `nix-store` calls.
+    async fn fetch_deps_with_fake(
+        name: &str,
+        closure: ClosureKind,
+        outs: Vec<DrvOut>,
+    ) -> (Dependencies, usize) {
+        let dir = std::env::temp_dir().join(format!(
+            "nixci-test-nix-store-{}-{}",
+            name,
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cmd = NixStoreCmd {
+            program: fake_nix_store(&dir),
+            batch_size: 100,
+            jobs: 4,
+            store: None,
+        };
+        let deps = cmd.fetch_deps(closure, outs).await.unwrap();
+
+        let calls = std::fs::read_to_string(dir.join("calls.log")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        (deps, calls.lines().count())
+    }
+
+    fn count_drvs(paths: &[StorePath]) -> usize {
+        paths
+            .iter()
+            .filter(|p| matches!(p, StorePath::Drv(_)))
+            .count()
+    }
+
+    #[test]
+    fn test_store_path_new() {
+        assert_eq!(
+            StorePath::new(PathBuf::from("/nix/store/abc-foo.drv")),
+            StorePath::Drv(PathBuf::from("/nix/store/abc-foo.drv"))
+        );
+        assert_eq!(
+            StorePath::new(PathBuf::from("/nix/store/abc-foo")),
+            StorePath::Other(PathBuf::from("/nix/store/abc-foo"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fetch_all_deps_batched() {
+        let (deps, calls) = fetch_deps_with_fake("all", ClosureKind::All, many_outs()).await;
+        // 10 batches each of derivers, their outputs, then requisites
+        assert_eq!(calls, 30);
+        // Each output and its deriver, plus glibc and gcc (and their derivers), once
+        assert_eq!(deps.paths.len(), 2004);
+        assert_eq!(count_drvs(&deps.paths), 1002);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_runtime_deps() {
+        let (deps, calls) =
+            fetch_deps_with_fake("runtime", ClosureKind::Runtime, many_outs()).await;
+        assert_eq!(calls, 10);
+        // Each output, plus glibc
+        assert_eq!(deps.paths.len(), 1001);
+        assert_eq!(count_drvs(&deps.paths), 0);
+        assert!(!deps
+            .paths
+            .contains(&StorePath::Other(PathBuf::from("/nix/store/gcc"))));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_build_deps() {
+        let (deps, calls) = fetch_deps_with_fake("build", ClosureKind::Build, many_outs()).await;
+        // derivers, their outputs, references, then requisites of the 2 shared inputs
+        assert_eq!(calls, 31);
+        // Each deriver, plus glibc and gcc (and their derivers)
+        assert_eq!(deps.paths.len(), 1004);
+        assert_eq!(count_drvs(&deps.paths), 1002);
+        assert!(!deps
+            .paths
+            .contains(&StorePath::Other(PathBuf::from("/nix/store/out-0"))));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_deps_without_deriver() {
+        let outs = ["out-1", "cached-1", "gced-1"]
+            .map(|p| DrvOut(PathBuf::from(format!("/nix/store/{}", p))))
+            .to_vec();
+        let (deps, _) = fetch_deps_with_fake("fallback", ClosureKind::All, outs).await;
+        assert_eq!(
+            deps.without_deriver,
+            vec![
+                PathBuf::from("/nix/store/cached-1"),
+                PathBuf::from("/nix/store/gced-1")
+            ]
+        );
+        // The closure of out-1's deriver, plus the runtime closure of the others
+        assert_eq!(deps.paths.len(), 8);
+        assert!(deps
+            .paths
+            .contains(&StorePath::Other(PathBuf::from("/nix/store/cached-1"))));
+    }
+
+    #[tokio::test]
+    async fn test_closure_size() {
+        let dir =
+            std::env::temp_dir().join(format!("nixci-test-nix-store-size-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cmd = NixStoreCmd {
+            program: fake_nix_store(&dir),
+            ..NixStoreCmd::default()
+        };
+        let size = cmd
+            .closure_size(&[
+                PathBuf::from("/nix/store/out-1"),
+                PathBuf::from("/nix/store/out-2"),
+            ])
+            .await
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        // out-1, out-2 and the shared glibc, 100 bytes each
+        assert_eq!(size, 300);
+    }
+}
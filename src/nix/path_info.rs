@@ -0,0 +1,547 @@
+//! Query the Nix store using `nix path-info` and `nix derivation show`
+//!
+//! This is an alternative to [super::nix_store::NixStoreCmd], which uses the
+//! legacy `nix-store` command. Unlike the latter, it respects the global
+//! [NixCmd] options, and exposes path metadata as typed structs.
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    path::{Path, PathBuf},
+};
+
+use nix_rs::command::{CommandError, NixCmd, NixCmdError};
+use serde::{Deserialize, Serialize};
+
+use super::nix_store::{in_batches, ClosureKind, Dependencies, DrvOut, StorePath};
+
+/// The default Nix store directory, relative to which newer Nix versions print store paths
+const STORE_DIR: &str = "/nix/store";
+
+/// Metadata of a valid store path, as reported by `nix path-info --json`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PathInfo {
+    /// The store path
+    #[serde(default)]
+    pub path: PathBuf,
+    /// Hash of the NAR serialisation of the path, eg: `sha256-...`
+    pub nar_hash: String,
+    /// Size of the NAR serialisation of the path, in bytes
+    pub nar_size: u64,
+    /// Total NAR size of the path's closure, in bytes (only with `--closure-size`)
+    #[serde(default)]
+    pub closure_size: Option<u64>,
+    /// The store paths this path refers to
+    #[serde(default)]
+    pub references: Vec<PathBuf>,
+    /// The derivation that produced this path, if known
+    #[serde(default)]
+    pub deriver: Option<PathBuf>,
+    /// The signatures of the path, eg: `cache.nixos.org-1:...`
+    #[serde(default)]
+    pub signatures: Vec<String>,
+}
+
+/// The result of `nix path-info --json`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PathInfos {
+    /// Metadata of the valid paths
+    pub valid: Vec<PathInfo>,
+    /// The paths that are not valid in the store
+    pub invalid: Vec<PathBuf>,
+}
+
+impl PathInfos {
+    /// Parse the JSON output of `nix path-info --json`
+    ///
+    /// Nix >= 2.19 prints an object keyed by store path, with `null` for
+    /// invalid paths; older versions print a list of objects with a `path`
+    /// field, and `"valid": false` for invalid paths.
+    pub fn from_json(json: serde_json::Value) -> serde_json::Result<PathInfos> {
+        let mut infos = PathInfos::default();
+        let entries: Vec<(Option<String>, serde_json::Value)> = match json {
+            serde_json::Value::Object(m) => m.into_iter().map(|(k, v)| (Some(k), v)).collect(),
+            v => Vec::<serde_json::Value>::deserialize(v)?
+                .into_iter()
+                .map(|v| (None, v))
+                .collect(),
+        };
+        for (key, value) in entries {
+            let path = key
+                .or_else(|| value.get("path")?.as_str().map(String::from))
+                .map(|p| absolute_store_path(Path::new(&p)))
+                .unwrap_or_default();
+            if value.is_null() || value.get("valid") == Some(&serde_json::Value::Bool(false)) {
+                infos.invalid.push(path);
+            } else {
+                let mut info = PathInfo::deserialize(value)?;
+                info.path = path;
+                infos.valid.push(info);
+            }
+        }
+        Ok(infos)
+    }
+}
+
+/// A derivation, as printed by `nix derivation show`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Derivation {
+    /// The system the derivation is built on
+    pub system: String,
+    /// The outputs of the derivation, by name
+    pub outputs: BTreeMap<String, DerivationOutput>,
+    /// The derivations whose outputs are inputs to this one
+    #[serde(default)]
+    pub input_drvs: BTreeMap<PathBuf, serde_json::Value>,
+    /// The source paths that are inputs to this derivation
+    #[serde(default)]
+    pub input_srcs: Vec<PathBuf>,
+}
+
+/// An output of a [Derivation]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DerivationOutput {
+    /// The output path, unless the derivation is content-addressed
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+}
+
+impl Derivation {
+    /// Parse the JSON output of `nix derivation show`, keyed by absolute derivation path
+    pub fn parse_show(
+        json: serde_json::Value,
+    ) -> serde_json::Result<BTreeMap<PathBuf, Derivation>> {
+        let drvs: BTreeMap<PathBuf, Derivation> = serde_json::from_value(json)?;
+        Ok(drvs
+            .into_iter()
+            .map(|(path, drv)| (absolute_store_path(&path), drv.absolute()))
+            .collect())
+    }
+
+    /// The output paths of this derivation that are known
+    pub fn output_paths(&self) -> impl Iterator<Item = &PathBuf> {
+        self.outputs.values().filter_map(|o| o.path.as_ref())
+    }
+
+    fn absolute(self) -> Derivation {
+        Derivation {
+            outputs: self
+                .outputs
+                .into_iter()
+                .map(|(name, o)| {
+                    let path = o.path.map(|p| absolute_store_path(&p));
+                    (name, DerivationOutput { path })
+                })
+                .collect(),
+            input_drvs: self
+                .input_drvs
+                .into_iter()
+                .map(|(p, v)| (absolute_store_path(&p), v))
+                .collect(),
+            input_srcs: self
+                .input_srcs
+                .iter()
+                .map(|p| absolute_store_path(p))
+                .collect(),
+            ..self
+        }
+    }
+}
+
+/// Newer Nix versions print store paths without the store directory
+fn absolute_store_path(path: &Path) -> PathBuf {
+    if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        Path::new(STORE_DIR).join(path)
+    }
+}
+
+/// Query the Nix store using `nix path-info` and `nix derivation show`
+#[derive(Debug, Clone)]
+pub struct NixPathInfoCmd {
+    /// The `nix` command, whose global options are respected
+    pub nixcmd: NixCmd,
+    /// The store to query, instead of the default one
+    pub store: Option<String>,
+    /// Maximum number of paths to pass to a single `nix` invocation
+    pub batch_size: usize,
+    /// Number of `nix` invocations to run concurrently
+    pub jobs: usize,
+}
+
+impl NixPathInfoCmd {
+    pub fn new(nixcmd: NixCmd, store: Option<String>) -> Self {
+        NixPathInfoCmd {
+            nixcmd,
+            store,
+            batch_size: 256,
+            jobs: 4,
+        }
+    }
+
+    /// Run `nix path-info --json` with the given extra flags on `paths`
+    ///
+    /// Some Nix versions fail outright if any path is invalid, so callers
+    /// that expect invalid paths should use [NixPathInfoCmd::query_valid] instead.
+    pub async fn path_info(
+        &self,
+        flags: &[&str],
+        paths: &[PathBuf],
+    ) -> Result<PathInfos, NixCmdError> {
+        let mut infos = PathInfos::default();
+        let results = in_batches(paths, self.batch_size, self.jobs, |batch| async move {
+            nix_path_info(&self.nixcmd, self.store.as_deref(), flags, batch)
+                .await
+                .map(|i| vec![i])
+        })
+        .await?;
+        for result in results {
+            infos.valid.extend(result.valid);
+            infos.invalid.extend(result.invalid);
+        }
+        Ok(infos)
+    }
+
+    /// Return those of `paths` that are valid
+    pub async fn query_valid(&self, paths: &[PathBuf]) -> Result<HashSet<PathBuf>, NixCmdError> {
+        let results = in_batches(paths, self.batch_size, self.jobs, |batch| async move {
+            query_valid_paths(&self.nixcmd, self.store.as_deref(), batch)
+                .await
+                .map(|v| v.into_iter().collect::<Vec<_>>())
+        })
+        .await?;
+        Ok(results.into_iter().collect())
+    }
+
+    /// Return the derivations in the closure of the given derivations, using `nix derivation show --recursive`
+    pub async fn derivation_show(
+        &self,
+        drv_paths: &[PathBuf],
+    ) -> Result<BTreeMap<PathBuf, Derivation>, NixCmdError> {
+        let results = in_batches(drv_paths, self.batch_size, self.jobs, |batch| async move {
+            nix_derivation_show(&self.nixcmd, self.store.as_deref(), batch)
+                .await
+                .map(|d| vec![d])
+        })
+        .await?;
+        Ok(results.into_iter().flatten().collect())
+    }
+
+    /// Fetch the dependencies of given [DrvOut]s selected by [ClosureKind]
+    ///
+    /// This is the counterpart of [super::nix_store::NixStoreCmd::fetch_deps].
+    pub async fn fetch_deps(
+        &self,
+        closure: ClosureKind,
+        out_paths: Vec<DrvOut>,
+    ) -> Result<Dependencies, NixCmdError> {
+        let out_paths: Vec<PathBuf> = out_paths
+            .into_iter()
+            .map(|DrvOut(p)| p)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        if closure == ClosureKind::Runtime {
+            return Ok(Dependencies {
+                paths: self.closure_of(out_paths).await?,
+                without_deriver: vec![],
+            });
+        }
+
+        // Find the derivers of the outputs, and which of them are valid
+        let infos = self.path_info(&[], &out_paths).await?;
+        let derivers: Vec<PathBuf> = infos
+            .valid
+            .iter()
+            .filter_map(|i| i.deriver.clone())
+            .map(|p| absolute_store_path(&p))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        let valid_drvs = self.query_valid(&derivers).await?;
+        let mut without_deriver = infos.invalid;
+        let mut drvs = BTreeSet::new();
+        for info in infos.valid {
+            match info.deriver.map(|p| absolute_store_path(&p)) {
+                Some(drv) if valid_drvs.contains(&drv) => {
+                    drvs.insert(drv);
+                }
+                _ => without_deriver.push(info.path),
+            }
+        }
+        without_deriver.sort();
+        let drvs: Vec<PathBuf> = drvs.into_iter().collect();
+
+        let all_drvs = self.derivation_show(&drvs).await?;
+        let mut roots: BTreeSet<PathBuf> = BTreeSet::new();
+        let included: Vec<&PathBuf> = match closure {
+            ClosureKind::Build => {
+                // The inputs of the derivations, rather than the derivations themselves
+                for drv in &drvs {
+                    if let Some(d) = all_drvs.get(drv) {
+                        roots.extend(d.input_srcs.iter().cloned());
+                        roots.extend(d.input_drvs.keys().cloned());
+                    }
+                }
+                let inputs: Vec<PathBuf> = roots.iter().cloned().collect();
+                reachable_drvs(&all_drvs, &inputs)
+            }
+            _ => {
+                roots.extend(drvs.iter().cloned());
+                all_drvs.keys().collect()
+            }
+        };
+        let outputs: Vec<PathBuf> = included
+            .into_iter()
+            .filter_map(|drv| all_drvs.get(drv))
+            .flat_map(Derivation::output_paths)
+            .cloned()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        roots.extend(self.query_valid(&outputs).await?);
+        roots.extend(without_deriver.iter().cloned());
+
+        let mut paths: BTreeSet<StorePath> = self
+            .closure_of(roots.into_iter().collect())
+            .await?
+            .into_iter()
+            .collect();
+        if closure == ClosureKind::Build {
+            paths.extend(drvs.into_iter().map(StorePath::Drv));
+        }
+        Ok(Dependencies {
+            paths: paths.into_iter().collect(),
+            without_deriver,
+        })
+    }
+
+    /// Return the total NAR size, in bytes, of the runtime closure of the given paths
+    pub async fn closure_size(&self, paths: &[PathBuf]) -> Result<u64, NixCmdError> {
+        let infos = self.path_info(&["--recursive"], paths).await?;
+        // Batches may overlap in their closures.
+        let sizes: BTreeMap<PathBuf, u64> = infos
+            .valid
+            .into_iter()
+            .map(|i| (i.path, i.nar_size))
+            .collect();
+        Ok(sizes.values().sum())
+    }
+
+    /// Return the closure of the given valid paths, in sorted order
+    async fn closure_of(&self, paths: Vec<PathBuf>) -> Result<Vec<StorePath>, NixCmdError> {
+        let infos = self.path_info(&["--recursive"], &paths).await?;
+        let paths: BTreeSet<StorePath> = infos
+            .valid
+            .into_iter()
+            .map(|i| StorePath::new(i.path))
+            .collect();
+        Ok(paths.into_iter().collect())
+    }
+}
+
+/// The derivations in `drvs` reachable from `roots` via `inputDrvs`, including `roots` themselves
+fn reachable_drvs<'a>(
+    drvs: &'a BTreeMap<PathBuf, Derivation>,
+    roots: &[PathBuf],
+) -> Vec<&'a PathBuf> {
+    let mut seen: BTreeSet<&PathBuf> = BTreeSet::new();
+    let mut stack: Vec<&PathBuf> = roots
+        .iter()
+        .filter_map(|r| drvs.get_key_value(r).map(|(k, _)| k))
+        .collect();
+    while let Some(drv) = stack.pop() {
+        if seen.insert(drv) {
+            if let Some(d) = drvs.get(drv) {
+                stack.extend(
+                    d.input_drvs
+                        .keys()
+                        .filter_map(|i| drvs.get_key_value(i).map(|(k, _)| k)),
+                );
+            }
+        }
+    }
+    seen.into_iter().collect()
+}
+
+/// Return those of `paths` that are valid in `store` (or the default store), querying `nix path-info`
+///
+/// Some Nix versions fail outright if any path is invalid, in which case each
+/// path is queried individually. Other errors (eg: an unreachable store) are
+/// returned as is.
+pub async fn query_valid_paths(
+    cmd: &NixCmd,
+    store: Option<&str>,
+    paths: &[PathBuf],
+) -> Result<HashSet<PathBuf>, NixCmdError> {
+    if paths.is_empty() {
+        return Ok(HashSet::new());
+    }
+    let valid_paths = |infos: PathInfos| -> HashSet<PathBuf> {
+        infos.valid.into_iter().map(|i| i.path).collect()
+    };
+    match nix_path_info(cmd, store, &[], paths).await {
+        Ok(infos) => Ok(valid_paths(infos)),
+        Err(err) if is_invalid_path_error(&err) => {
+            let mut valid = HashSet::new();
+            for path in paths {
+                match nix_path_info(cmd, store, &[], std::slice::from_ref(path)).await {
+                    Ok(infos) => valid.extend(valid_paths(infos)),
+                    Err(err) if is_invalid_path_error(&err) => {}
+                    Err(err) => return Err(err),
+                }
+            }
+            Ok(valid)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Whether `nix path-info` failed because a queried path is not valid in the store
+fn is_invalid_path_error(err: &NixCmdError) -> bool {
+    match err {
+        NixCmdError::CmdError(CommandError::ProcessFailed {
+            stderr: Some(stderr),
+            ..
+        }) => stderr
+            .lines()
+            .any(|line| line.contains("error:") && line.contains("is not valid")),
+        _ => false,
+    }
+}
+
+async fn nix_path_info(
+    cmd: &NixCmd,
+    store: Option<&str>,
+    flags: &[&str],
+    paths: &[PathBuf],
+) -> Result<PathInfos, NixCmdError> {
+    let paths: Vec<String> = paths
+        .iter()
+        .map(|p| p.to_string_lossy().to_string())
+        .collect();
+    let mut args = vec!["path-info", "--json"];
+    args.extend(super::store::store_args(store));
+    args.extend(flags);
+    args.extend(paths.iter().map(String::as_str));
+    let json: serde_json::Value = cmd.run_with_args_expecting_json(&args).await?;
+    Ok(PathInfos::from_json(json)?)
+}
+
+async fn nix_derivation_show(
+    cmd: &NixCmd,
+    store: Option<&str>,
+    drv_paths: &[PathBuf],
+) -> Result<BTreeMap<PathBuf, Derivation>, NixCmdError> {
+    let drv_paths: Vec<String> = drv_paths
+        .iter()
+        .map(|p| p.to_string_lossy().to_string())
+        .collect();
+    let mut args = vec!["derivation", "show", "--recursive"];
+    args.extend(super::store::store_args(store));
+    args.extend(drv_paths.iter().map(String::as_str));
+    let json: serde_json::Value = cmd.run_with_args_expecting_json(&args).await?;
+    Ok(Derivation::parse_show(json)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_invalid_path_error() {
+        let failed = |stderr: &str| {
+            NixCmdError::CmdError(CommandError::ProcessFailed {
+                stderr: Some(stderr.to_string()),
+                exit_code: Some(1),
+            })
+        };
+        assert!(is_invalid_path_error(&failed(
+            "error: path '/nix/store/a-foo' is not valid\n"
+        )));
+        assert!(!is_invalid_path_error(&failed(
+            "error: unable to download 'https://cache.example.org/nix-cache-info': Couldn't resolve host name (6)\n"
+        )));
+        assert!(!is_invalid_path_error(&NixCmdError::CmdError(
+            CommandError::ProcessFailed {
+                stderr: None,
+                exit_code: Some(1),
+            }
+        )));
+    }
+
+    #[test]
+    fn test_path_info_json() {
+        let info = serde_json::json!({
+            "narHash": "sha256-abc",
+            "narSize": 100,
+            "closureSize": 300,
+            "references": ["/nix/store/b-glibc"],
+            "deriver": "/nix/store/c-foo.drv",
+            "signatures": ["cache.nixos.org-1:xyz"],
+        });
+        let mut old_info = info.clone();
+        old_info["path"] = "/nix/store/a-foo".into();
+        let new = serde_json::json!({"/nix/store/a-foo": info, "/nix/store/d-bar": null});
+        let old = serde_json::json!([old_info, {"path": "/nix/store/d-bar", "valid": false}]);
+        let expected = PathInfos {
+            valid: vec![PathInfo {
+                path: PathBuf::from("/nix/store/a-foo"),
+                nar_hash: "sha256-abc".to_string(),
+                nar_size: 100,
+                closure_size: Some(300),
+                references: vec![PathBuf::from("/nix/store/b-glibc")],
+                deriver: Some(PathBuf::from("/nix/store/c-foo.drv")),
+                signatures: vec!["cache.nixos.org-1:xyz".to_string()],
+            }],
+            invalid: vec![PathBuf::from("/nix/store/d-bar")],
+        };
+        assert_eq!(PathInfos::from_json(new).unwrap(), expected);
+        assert_eq!(PathInfos::from_json(old).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_derivation_show() {
+        let json = serde_json::json!({
+            "a-foo.drv": {
+                "system": "x86_64-linux",
+                "outputs": {"out": {"path": "a-foo"}, "dev": {"path": "/nix/store/a-foo-dev"}},
+                "inputDrvs": {"/nix/store/b-gcc.drv": {"outputs": ["out"]}},
+                "inputSrcs": ["/nix/store/c-src"],
+            },
+            "/nix/store/b-gcc.drv": {
+                "system": "x86_64-linux",
+                "outputs": {"out": {"path": "/nix/store/b-gcc"}},
+                "inputDrvs": {},
+                "inputSrcs": [],
+            },
+            "/nix/store/d-unused.drv": {
+                "system": "x86_64-linux",
+                "outputs": {"out": {}},
+            },
+        });
+        let drvs = Derivation::parse_show(json).unwrap();
+        let foo = &drvs[Path::new("/nix/store/a-foo.drv")];
+        assert_eq!(
+            foo.output_paths().collect::<Vec<_>>(),
+            vec![
+                &PathBuf::from("/nix/store/a-foo-dev"),
+                &PathBuf::from("/nix/store/a-foo")
+            ]
+        );
+        assert_eq!(
+            reachable_drvs(&drvs, &[PathBuf::from("/nix/store/a-foo.drv")]),
+            vec![
+                &PathBuf::from("/nix/store/a-foo.drv"),
+                &PathBuf::from("/nix/store/b-gcc.drv")
+            ]
+        );
+        assert_eq!(
+            drvs[Path::new("/nix/store/d-unused.drv")]
+                .output_paths()
+                .count(),
+            0
+        );
+    }
+}
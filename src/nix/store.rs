@@ -0,0 +1,12 @@
+//! Select the Nix store used by the Nix invocations of nixci
+
+/// The `--store` arguments selecting the store at `store`, if any
+///
+/// Any store URI accepted by `nix --store` can be used, eg: `ssh-ng://host` or
+/// a chroot store like `/tmp/store`. Both `nix` and `nix-store` accept them.
+pub fn store_args(store: Option<&str>) -> Vec<&str> {
+    match store {
+        Some(uri) => vec!["--store", uri],
+        None => vec![],
+    }
+}
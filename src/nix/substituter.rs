@@ -0,0 +1,15 @@
+//! Query substituters (binary caches) for the availability of store paths
+use std::{collections::HashSet, path::PathBuf};
+
+use nix_rs::command::{NixCmd, NixCmdError};
+
+use super::path_info::query_valid_paths;
+
+/// Return those of `paths` that are available in the store at `store_uri`
+pub async fn query_cached_paths(
+    cmd: &NixCmd,
+    store_uri: &str,
+    paths: &[PathBuf],
+) -> Result<HashSet<PathBuf>, NixCmdError> {
+    query_valid_paths(cmd, Some(store_uri), paths).await
+}
@@ -6,6 +6,18 @@ use nix_rs::{
     flake::{system::System, url::FlakeUrl},
 };
 
+use super::devour_flake::write_generated_files;
+
+/// Systems lists recognized by `github:nix-systems/*`
+///
+/// These can be passed by name wherever a [SystemsListFlakeRef] is expected.
+pub const KNOWN_NIX_SYSTEMS: [&str; 4] = [
+    "aarch64-darwin",
+    "aarch64-linux",
+    "x86_64-darwin",
+    "x86_64-linux",
+];
+
 /// A flake URL that references a list of systems ([SystemsList])
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SystemsListFlakeRef(pub FlakeUrl);
@@ -13,14 +25,7 @@ pub struct SystemsListFlakeRef(pub FlakeUrl);
 impl FromStr for SystemsListFlakeRef {
     type Err = String;
     fn from_str(s: &str) -> std::result::Result<SystemsListFlakeRef, String> {
-        // Systems lists recognized by `github:nix-system/*`
-        let known_nix_systems = [
-            "aarch64-darwin",
-            "aarch64-linux",
-            "x86_64-darwin",
-            "x86_64-linux",
-        ];
-        let url = if known_nix_systems.contains(&s) {
+        let url = if KNOWN_NIX_SYSTEMS.contains(&s) {
             format!("github:nix-systems/{}", s)
         } else {
             s.to_string()
@@ -29,6 +34,71 @@ impl FromStr for SystemsListFlakeRef {
     }
 }
 
+impl SystemsListFlakeRef {
+    /// A flake URL referencing exactly the given systems
+    ///
+    /// Uses `github:nix-systems/<system>` for a single known system, or else
+    /// a flake generated by nixci.
+    pub fn from_systems(systems: &[System]) -> Result<SystemsListFlakeRef> {
+        if let [system] = systems {
+            let url = SystemsListFlakeRef::from_str(system.as_ref()).unwrap();
+            if SystemsList::from_known_flake(&url).is_some() {
+                return Ok(url);
+            }
+        }
+        let default_nix = format!(
+            "# Generated by nixci\n[ {} ]\n",
+            systems
+                .iter()
+                .map(|s| format!("\"{}\"", s))
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+        let dir = write_generated_files(&[
+            ("flake.nix", "{ outputs = _: { }; }\n"),
+            ("default.nix", &default_nix),
+        ])?;
+        Ok(SystemsListFlakeRef(FlakeUrl(format!(
+            "path:{}",
+            dir.display()
+        ))))
+    }
+}
+
+/// Either a single system, or a flake reference to a list of systems
+///
+/// Values containing `:` or `/`, or starting with `.`, (eg: `github:nix-systems/default`
+/// or `./systems`) are flake references; anything else is a system name (eg:
+/// `x86_64-linux`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SystemOrList {
+    System(System),
+    List(SystemsListFlakeRef),
+}
+
+impl FromStr for SystemOrList {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<SystemOrList, String> {
+        if s.contains(':') || s.contains('/') || s.starts_with('.') {
+            Ok(SystemOrList::List(SystemsListFlakeRef(FlakeUrl(
+                s.to_string(),
+            ))))
+        } else {
+            Ok(SystemOrList::System(s.into()))
+        }
+    }
+}
+
+impl SystemOrList {
+    /// The systems referenced by this value, evaluating the flake if need be
+    pub async fn systems(&self, cmd: &NixCmd) -> Result<Vec<System>> {
+        match self {
+            SystemOrList::System(system) => Ok(vec![system.clone()]),
+            SystemOrList::List(url) => Ok(SystemsList::from_flake(cmd, url).await?.0),
+        }
+    }
+}
+
 pub struct SystemsList(pub Vec<System>);
 
 impl SystemsList {
@@ -138,6 +208,16 @@ mod tests {
         assert_systems_list("github:nix-systems/empty", vec![]).await;
     }
 
+    #[tokio::test]
+    async fn test_systems_list_from_systems() {
+        let systems: Vec<System> = vec!["aarch64-darwin".into(), "riscv64-linux".into()];
+        let url = SystemsListFlakeRef::from_systems(&systems).unwrap();
+        let got = SystemsList::from_flake(&NixCmd::default(), &url)
+            .await
+            .unwrap();
+        assert_eq!(got.0, systems);
+    }
+
     async fn assert_systems_list(url: &str, expected: Vec<System>) {
         let cmd = NixCmd::default();
         let systems = SystemsList::from_flake(&cmd, &SystemsListFlakeRef(url.into()))
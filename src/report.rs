@@ -0,0 +1,347 @@
+//! Machine-readable report of a `nixci build` run
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use colored::Colorize;
+use nix_rs::flake::{system::System, url::FlakeUrl};
+use serde::{Serialize, Serializer};
+
+use crate::{
+    config::SubFlakish,
+    nix::devour_flake::{DevourFlakeOutput, OutputCategory},
+};
+
+/// Report of a `nixci build` run, containing an entry for each sub-flake
+///
+/// All keys and tags are camelCase, like those of the nixci configuration.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildReport {
+    /// The systems that were built for
+    pub systems: Vec<System>,
+
+    /// Per sub-flake reports, in the order they were processed
+    pub subflakes: Vec<SubflakeReport>,
+
+    /// Outputs without a valid deriver, for which `--print-all-dependencies`
+    /// included only the runtime closure
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub without_deriver: Vec<PathBuf>,
+
+    /// Closure size, in bytes, of each output, with `--closure-report`
+    ///
+    /// See [crate::closure_report::ClosureReport] for the keys.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub closure_sizes: BTreeMap<String, u64>,
+}
+
+/// Report for a single sub-flake of a nixci configuration
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SubflakeReport {
+    /// Configuration name (nixci.<name>)
+    pub config: String,
+
+    /// Sub-flake name (nixci.<config>.<name>)
+    pub name: String,
+
+    /// The flake URL of the sub-flake
+    pub flake_url: FlakeUrl,
+
+    /// The sub-flake configuration
+    pub subflake: SubFlakish,
+
+    /// The categories of flake outputs built
+    pub categories: Vec<OutputCategory>,
+
+    /// How long it took to process the sub-flake
+    #[serde(serialize_with = "serialize_duration_secs")]
+    pub duration: Duration,
+
+    /// What happened to the sub-flake
+    #[serde(flatten)]
+    pub result: SubflakeResult,
+}
+
+impl SubflakeReport {
+    /// The fully qualified name of the sub-flake (`<config>.<name>`)
+    pub fn full_name(&self) -> String {
+        format!("{}.{}", self.config, self.name)
+    }
+}
+
+/// The result of processing a sub-flake
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum SubflakeResult {
+    /// The sub-flake was built successfully
+    Success {
+        #[serde(flatten)]
+        outputs: DevourFlakeOutput,
+    },
+    /// The sub-flake failed to build
+    Failure { error: String },
+    /// The sub-flake was not built
+    Skipped {
+        #[serde(flatten)]
+        reason: SkipReason,
+    },
+}
+
+/// Why a sub-flake was not built
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(
+    tag = "reason",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum SkipReason {
+    /// Another sub-flake was selected, eg: `nixci build .#default.dev`
+    Deselected,
+    /// None of the sub-flake's `systems` are being built for, and no builder
+    /// is available for them
+    UnsupportedSystems {
+        /// The systems the sub-flake is restricted to
+        subflake_systems: Vec<System>,
+        /// The systems being built for
+        build_systems: Vec<System>,
+    },
+    /// All its outputs are already in a substituter (`--skip-cached`)
+    AlreadyCached,
+    /// No category of outputs is selected, eg: by `--exclude` along with the
+    /// sub-flake's own `exclude`
+    NoOutputs,
+    /// A sub-flake it depends on (`dependsOn`) failed, or was itself skipped
+    /// for this reason
+    DependencyFailed {
+        /// The full name (`<config>.<name>`) of the dependency
+        dependency: String,
+    },
+    /// Its definition is identical to that of another sub-flake, which is
+    /// built instead (eg: across configurations, with `--all-configs`)
+    Duplicate {
+        /// The full name (`<config>.<name>`) of the sub-flake built instead
+        of: String,
+    },
+}
+
+impl std::fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let join = |systems: &[System]| {
+            systems
+                .iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        match self {
+            SkipReason::Deselected => write!(f, "deselected out"),
+            SkipReason::UnsupportedSystems {
+                subflake_systems,
+                build_systems,
+            } => write!(
+                f,
+                "only builds on {}, not {}",
+                join(subflake_systems),
+                join(build_systems)
+            ),
+            SkipReason::AlreadyCached => write!(f, "already cached"),
+            SkipReason::NoOutputs => write!(f, "no outputs selected"),
+            SkipReason::DependencyFailed { dependency } => {
+                write!(f, "dependency {} failed", dependency)
+            }
+            SkipReason::Duplicate { of } => write!(f, "same as {}", of),
+        }
+    }
+}
+
+impl BuildReport {
+    /// Return the outputs of all successfully built sub-flakes
+    pub fn outputs(&self) -> impl Iterator<Item = &DevourFlakeOutput> {
+        self.subflakes.iter().filter_map(|r| match &r.result {
+            SubflakeResult::Success { outputs } => Some(outputs),
+            _ => None,
+        })
+    }
+
+    /// Return the sub-flakes that failed to build
+    pub fn failures(&self) -> impl Iterator<Item = &SubflakeReport> {
+        self.subflakes
+            .iter()
+            .filter(|r| matches!(r.result, SubflakeResult::Failure { .. }))
+    }
+
+    /// Log a pass/fail summary table of all sub-flakes
+    pub fn print_summary(&self) {
+        let width = self
+            .subflakes
+            .iter()
+            .map(|r| r.full_name().len())
+            .max()
+            .unwrap_or(0);
+        tracing::info!("{}", "🍏 Summary".bold());
+        for r in &self.subflakes {
+            let name = format!("{:width$}", r.full_name());
+            let status = match &r.result {
+                SubflakeResult::Success { .. } => "✅ passed".green(),
+                SubflakeResult::Failure { .. } => "❌ failed".red(),
+                SubflakeResult::Skipped { reason } => format!("⏩ skipped ({})", reason).dimmed(),
+            };
+            tracing::info!("   {}  {}  {:.1}s", name, status, r.duration.as_secs_f64());
+        }
+    }
+
+    /// Return the sub-flakes that were skipped, along with why
+    pub fn skipped(&self) -> impl Iterator<Item = (&SubflakeReport, &SkipReason)> {
+        self.subflakes.iter().filter_map(|r| match &r.result {
+            SubflakeResult::Skipped { reason } => Some((r, reason)),
+            _ => None,
+        })
+    }
+
+    /// Log the sub-flakes that were skipped, along with why
+    pub fn print_skipped(&self) {
+        let skipped: Vec<_> = self.skipped().collect();
+        if skipped.is_empty() {
+            return;
+        }
+        let width = skipped
+            .iter()
+            .map(|(r, _)| r.full_name().len())
+            .max()
+            .unwrap_or(0);
+        tracing::info!("{}", "⏩ Skipped".bold());
+        for (r, reason) in skipped {
+            let name = format!("{:width$}", r.full_name());
+            tracing::info!("   {}  {}", name, reason.to_string().dimmed());
+        }
+    }
+
+    /// Write the report as JSON to the given file
+    pub fn write_to(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, json)
+            .with_context(|| format!("Unable to write report to {}", path.display()))
+    }
+}
+
+fn serialize_duration_secs<S>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nix::nix_store::DrvOut;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_subflake_report_json() {
+        let outputs = DevourFlakeOutput {
+            out_paths: HashSet::from([
+                DrvOut(PathBuf::from("/nix/store/b-bar")),
+                DrvOut(PathBuf::from("/nix/store/a-foo")),
+            ]),
+            by_attr: BTreeMap::from([(
+                "packages.x86_64-linux.foo".to_string(),
+                vec![DrvOut(PathBuf::from("/nix/store/a-foo"))],
+            )]),
+        };
+        let report = SubflakeReport {
+            config: "default".to_string(),
+            name: "dev".to_string(),
+            flake_url: FlakeUrl("github:srid/haskell-flake?dir=dev".to_string()),
+            subflake: SubFlakish {
+                dir: "dev".to_string(),
+                ..SubFlakish::default()
+            },
+            categories: vec![OutputCategory::Packages, OutputCategory::DevShells],
+            duration: Duration::from_millis(1500),
+            result: SubflakeResult::Success { outputs },
+        };
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            serde_json::json!({
+                "config": "default",
+                "name": "dev",
+                "flakeUrl": "github:srid/haskell-flake?dir=dev",
+                "subflake": {
+                    "dir": "dev",
+                    "overrideInputs": {},
+                    "systems": null,
+                    "extraArgs": [],
+                    "includeOutputs": [],
+                    "excludeOutputs": [],
+                    "runsOn": {},
+                    "builders": [],
+                    "dependsOn": [],
+                },
+                "categories": ["packages", "devShells"],
+                "duration": 1.5,
+                "status": "success",
+                "outputs": ["/nix/store/a-foo", "/nix/store/b-bar"],
+                "outputsByAttr": {
+                    "packages.x86_64-linux.foo": ["/nix/store/a-foo"],
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn test_skipped_json() {
+        let result = SubflakeResult::Skipped {
+            reason: SkipReason::UnsupportedSystems {
+                subflake_systems: vec!["aarch64-darwin".into()],
+                build_systems: vec!["x86_64-linux".into()],
+            },
+        };
+        assert_eq!(
+            serde_json::to_value(&result).unwrap(),
+            serde_json::json!({
+                "status": "skipped",
+                "reason": "unsupportedSystems",
+                "subflakeSystems": ["aarch64-darwin"],
+                "buildSystems": ["x86_64-linux"],
+            })
+        );
+        let result = SubflakeResult::Skipped {
+            reason: SkipReason::Deselected,
+        };
+        assert_eq!(
+            serde_json::to_value(&result).unwrap(),
+            serde_json::json!({"status": "skipped", "reason": "deselected"})
+        );
+        let result = SubflakeResult::Skipped {
+            reason: SkipReason::NoOutputs,
+        };
+        assert_eq!(
+            serde_json::to_value(&result).unwrap(),
+            serde_json::json!({"status": "skipped", "reason": "noOutputs"})
+        );
+        let result = SubflakeResult::Skipped {
+            reason: SkipReason::Duplicate {
+                of: "default.dev".to_string(),
+            },
+        };
+        assert_eq!(
+            serde_json::to_value(&result).unwrap(),
+            serde_json::json!({"status": "skipped", "reason": "duplicate", "of": "default.dev"})
+        );
+        let result = SubflakeResult::Skipped {
+            reason: SkipReason::DependencyFailed {
+                dependency: "default.lib".to_string(),
+            },
+        };
+        assert_eq!(
+            serde_json::to_value(&result).unwrap(),
+            serde_json::json!({"status": "skipped", "reason": "dependencyFailed", "dependency": "default.lib"})
+        );
+    }
+}
@@ -93,6 +93,56 @@ mod integration_test {
         Ok(())
     }
 
+    #[tokio::test]
+    /// Push the built outputs to a local binary cache
+    async fn test_haskell_multi_nix_push_to() -> anyhow::Result<()> {
+        let cache = std::env::temp_dir().join(format!("nixci-cache-{}", std::process::id()));
+        let to = format!("file://{}", cache.display());
+        let args = cli::CliArgs::parse_from([
+            "nixci",
+            "-v",
+            "build",
+            "--push-to",
+            &to,
+            "github:srid/haskell-multi-nix/c85563721c388629fa9e538a1d97274861bc8321",
+        ]);
+        let outs = nixci::nixci(args).await?;
+        assert!(!outs.is_empty());
+        for out in outs {
+            // A binary cache has a `<hash>.narinfo` for each store path
+            let name = out.as_path().file_name().unwrap().to_string_lossy();
+            let hash = name.split('-').next().unwrap();
+            assert!(
+                cache.join(format!("{}.narinfo", hash)).exists(),
+                "{} was not pushed",
+                out
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    /// Build in a chroot store, and check that all outputs (and their dependencies) are in it
+    async fn test_haskell_multi_nix_chroot_store() -> anyhow::Result<()> {
+        let store = std::env::temp_dir().join(format!("nixci-store-{}", std::process::id()));
+        let mut cmd = Command::cargo_bin("nixci")?;
+        cmd.arg("--store")
+            .arg(&store)
+            .arg("build")
+            .arg("--print-all-dependencies")
+            .arg("github:srid/haskell-multi-nix/c85563721c388629fa9e538a1d97274861bc8321");
+
+        let output = cmd.output()?;
+        assert!(output.status.success(), "nixci failed: {:?}", output);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.lines().count() > 0);
+        for line in stdout.lines() {
+            let path = store.join(line.trim_start_matches('/'));
+            assert!(path.exists(), "{} is not in {}", line, store.display());
+        }
+        Ok(())
+    }
+
     #[tokio::test]
     /// A test, with config
     async fn test_services_flake() -> anyhow::Result<()> {